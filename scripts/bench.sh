@@ -0,0 +1,76 @@
+#!/bin/bash
+# Measures the output throughput of teetty.
+#
+# Usage: scripts/bench.sh [BASELINE_REF] [MEGABYTES]
+#
+# If a baseline git ref is given, it's built in a temporary worktree and
+# measured side by side with the current tree.  Every measurement is the best
+# of `RUNS` (defaults to 3) runs.
+set -euo pipefail
+
+SCRIPT_DIR="$( cd "$( dirname "${BASH_SOURCE[0]}" )" && pwd )"
+cd $SCRIPT_DIR/..
+
+BASELINE="${1:-}"
+MEGABYTES="${2:-256}"
+RUNS="${RUNS:-3}"
+WORKDIR="$(mktemp -d)"
+
+cleanup() {
+  if [ -n "$BASELINE" ]; then
+    git worktree remove --force "$WORKDIR/baseline" 2> /dev/null || true
+  fi
+  rm -rf "$WORKDIR"
+}
+trap cleanup EXIT
+
+cargo build --release -p teetty
+cp target/release/teetty "$WORKDIR/teetty-current"
+BINARIES="current"
+
+if [ -n "$BASELINE" ]; then
+  git worktree add --detach "$WORKDIR/baseline" "$BASELINE" > /dev/null
+  (cd "$WORKDIR/baseline" && CARGO_TARGET_DIR="$WORKDIR/target" cargo build --release -p teetty)
+  cp "$WORKDIR/target/release/teetty" "$WORKDIR/teetty-baseline"
+  BINARIES="baseline current"
+fi
+
+head -c "${MEGABYTES}M" /dev/urandom | base64 > "$WORKDIR/data"
+BYTES=$(wc -c < "$WORKDIR/data")
+
+# Runs teetty a few times and prints the best throughput in MB/s.
+measure() {
+  local binary="$1"
+  local sink="$2"
+  shift 2
+  local start end
+  for _ in $(seq "$RUNS"); do
+    start=$(date +%s.%N)
+    if [ "$sink" = "pipe" ]; then
+      "$WORKDIR/teetty-$binary" "$@" -- cat "$WORKDIR/data" < /dev/null | cat > /dev/null
+    else
+      "$WORKDIR/teetty-$binary" "$@" -- cat "$WORKDIR/data" < /dev/null > /dev/null
+    fi
+    end=$(date +%s.%N)
+    echo "$start $end"
+  done | awk -v bytes="$BYTES" '
+    { if (!best || $2 - $1 < best) best = $2 - $1 }
+    END { printf "%8.1f MB/s", bytes / best / 1000000 }'
+}
+
+bench() {
+  local name="$1"
+  shift
+  printf "%-22s" "$name"
+  for binary in $BINARIES; do
+    printf "  %-8s %s" "$binary" "$(measure "$binary" "$@")"
+  done
+  echo
+}
+
+echo
+echo "Throughput of $((BYTES / 1000000)) MB of output:"
+bench "stdout to a pipe" pipe
+bench "stdout to /dev/null" null
+bench "with --out" pipe --out "$WORKDIR/out"
+bench "with --out --no-flush" pipe --out "$WORKDIR/out" --no-flush
@@ -1,81 +1,661 @@
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
 use std::io::Write;
+use std::mem::ManuallyDrop;
+use std::os::fd::{BorrowedFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 
-use anyhow::Error;
-use clap::{Arg, ArgAction, Command};
+use anyhow::{Context, Error};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 
-use tty_spawn::TtySpawn;
+use tty_spawn::{
+    ChildExitPolicy, FsyncPolicy, InputOverflowPolicy, LogHeaderFormat, OverflowPolicy,
+    ResourceLimit, StdinEofPolicy, Trigger, TtySpawn, DEFAULT_BUFFER_SIZE,
+};
+
+mod multi;
 
 fn execute() -> Result<i32, Error> {
     let matches = make_app().get_matches();
 
+    if let Some(("multi", matches)) = matches.subcommand() {
+        return multi::execute(matches);
+    }
     if matches.get_flag("version") {
         eprintln!("teetty {}", env!("CARGO_PKG_VERSION"));
         return Ok(0);
     }
+    if matches.get_flag("reset_terminal") {
+        tty_spawn::reset_terminal().context("cannot reset terminal")?;
+        return Ok(0);
+    }
 
     let mut spawn = TtySpawn::new_cmdline(matches.get_many::<OsString>("command").unwrap());
+
+    // The file descriptors are passed before any file is opened, otherwise
+    // one that is not open could be taken by one of our own files.  All of
+    // them are checked first as duplicating one could take the number of
+    // another one just the same.
+    let pass_fds = matches
+        .get_many::<i32>("pass_fd")
+        .unwrap_or_default()
+        .copied()
+        .collect::<Vec<_>>();
+    for &fd in &pass_fds {
+        // `fstat` fails with `EBADF` without opening anything.
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        file.metadata()
+            .with_context(|| format!("cannot pass file descriptor {}", fd))?;
+    }
+    for &fd in &pass_fds {
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        spawn
+            .pass_fd(borrowed, fd)
+            .with_context(|| format!("cannot pass file descriptor {}", fd))?;
+    }
+
     spawn.script_mode(matches.get_flag("script_mode"));
     spawn.flush(!matches.get_flag("no_flush"));
+    if let Some(ms) = matches.get_one::<u64>("flush_interval") {
+        spawn.flush_interval(Duration::from_millis(*ms));
+    }
+    spawn.fsync(
+        match matches.get_one::<String>("fsync").map(|x| x.as_str()) {
+            None | Some("never") => FsyncPolicy::Never,
+            Some("always") => FsyncPolicy::Always,
+            Some(ms) => FsyncPolicy::Interval(Duration::from_millis(
+                ms.parse().context("invalid fsync policy")?,
+            )),
+        },
+    );
     spawn.echo(!matches.get_flag("no_echo"));
     spawn.pager(!matches.get_flag("no_pager"));
     spawn.raw(!matches.get_flag("no_raw"));
 
-    if let Some(p) = matches.get_one::<PathBuf>("in_path") {
+    spawn.stdin_eof(
+        match matches.get_one::<String>("stdin_eof").unwrap().as_str() {
+            "ignore" => StdinEofPolicy::Ignore,
+            "hangup" => StdinEofPolicy::Hangup,
+            "wait" => StdinEofPolicy::Wait,
+            _ => StdinEofPolicy::Eof,
+        },
+    );
+    spawn.child_exit(
+        match matches.get_one::<String>("child_exit").unwrap().as_str() {
+            "exit" => ChildExitPolicy::Exit,
+            "kill" => ChildExitPolicy::Kill,
+            _ => ChildExitPolicy::Wait,
+        },
+    );
+    for p in matches.get_many::<PathBuf>("in_path").unwrap_or_default() {
         spawn.stdin_path(p)?;
     }
-    if let Some(p) = matches.get_one::<PathBuf>("out_path") {
-        spawn.stdout_path(p, matches.get_flag("truncate_out"))?;
+    spawn.input_buffer(
+        matches
+            .get_one::<usize>("in_buffer")
+            .copied()
+            .unwrap_or(DEFAULT_BUFFER_SIZE),
+        match matches.get_one::<String>("in_overflow").unwrap().as_str() {
+            "drop" => InputOverflowPolicy::Drop,
+            "error" => InputOverflowPolicy::Error,
+            _ => InputOverflowPolicy::Block,
+        },
+    );
+    spawn.output_buffer(
+        matches
+            .get_one::<usize>("out_buffer")
+            .copied()
+            .unwrap_or(DEFAULT_BUFFER_SIZE),
+        match matches.get_one::<String>("out_overflow").unwrap().as_str() {
+            "block" => OverflowPolicy::Block,
+            "drop-newest" => OverflowPolicy::DropNewest,
+            _ => OverflowPolicy::DropOldest,
+        },
+    );
+    for target in matches.get_many::<OsString>("out_path").unwrap_or_default() {
+        add_output(&mut spawn, target, matches.get_flag("truncate_out"))?;
+    }
+    if let Some(p) = matches.get_one::<PathBuf>("events_path") {
+        spawn.events_path(p, matches.get_flag("truncate_out"))?;
+    }
+    if let Some(p) = matches.get_one::<PathBuf>("status_path") {
+        spawn.status_path(p);
+    }
+    spawn.strip_osc(matches.get_flag("strip_osc"));
+    match matches.get_one::<String>("log_header").map(|x| x.as_str()) {
+        Some("comment") => {
+            spawn.log_header(LogHeaderFormat::Comment);
+        }
+        Some("json") => {
+            spawn.log_header(LogHeaderFormat::Json);
+        }
+        _ => {}
+    }
+    for pattern in matches.get_many::<String>("redact").unwrap_or_default() {
+        spawn
+            .redact(pattern)
+            .with_context(|| format!("invalid redact pattern '{}'", pattern))?;
+    }
+    for var in matches.get_many::<String>("redact_env").unwrap_or_default() {
+        if let Ok(value) = std::env::var(var) {
+            spawn.redact_literal(&value);
+        }
+    }
+    for value in matches.get_many::<String>("rlimit").unwrap_or_default() {
+        let (limit, soft, hard) = parse_rlimit(value)?;
+        spawn.rlimit(limit, soft, hard);
+    }
+    if let Some(mask) = matches.get_one::<String>("umask") {
+        spawn.umask(
+            u32::from_str_radix(mask, 8).with_context(|| format!("invalid umask '{}'", mask))?,
+        );
+    }
+    if let Some(&nice) = matches.get_one::<i32>("nice") {
+        spawn.nice(nice);
+    }
+    if let Some(&uid) = matches.get_one::<u32>("uid") {
+        spawn.uid(uid);
+    }
+    if let Some(&gid) = matches.get_one::<u32>("gid") {
+        spawn.gid(gid);
+    }
+    if let Some(groups) = matches.get_many::<u32>("groups") {
+        spawn.groups(&groups.copied().collect::<Vec<_>>());
+    }
+    for trigger in parse_triggers(&matches)? {
+        spawn.add_trigger(trigger);
+    }
+    if let Some(p) = matches.get_one::<PathBuf>("in_log_path") {
+        spawn.input_log_path(p, matches.get_flag("truncate_out"))?;
+        spawn.input_log_tagged(matches.get_flag("in_log_tagged"));
+        spawn.protect_noecho(matches.get_flag("protect_noecho"));
     }
 
     Ok(spawn.spawn()?)
 }
 
+/// Adds an output by target which is either a path or a socket address.
+fn add_output(spawn: &mut TtySpawn, target: &OsStr, truncate: bool) -> Result<(), Error> {
+    let target_bytes = target.as_bytes();
+    if let Some(path) = target_bytes.strip_prefix(b"unix:") {
+        spawn.stdout_unix_socket(OsStr::from_bytes(path));
+    } else if let Some(addr) = target_bytes.strip_prefix(b"tcp:") {
+        let addr = std::str::from_utf8(addr).context("invalid tcp address")?;
+        spawn
+            .stdout_tcp_socket(addr)
+            .with_context(|| format!("invalid tcp address '{}'", addr))?;
+    } else {
+        spawn.stdout_path(target, truncate)?;
+    }
+    Ok(())
+}
+
+/// Builds the triggers from the `--on` arguments and the options following them.
+fn parse_triggers(matches: &ArgMatches) -> Result<Vec<Trigger>, Error> {
+    struct TriggerArgs<'a> {
+        pattern: &'a str,
+        response: Option<Vec<u8>>,
+        repeat: bool,
+        cooldown: Option<u64>,
+    }
+
+    fn indexed<'a, T: Clone + Send + Sync + 'static>(
+        matches: &'a ArgMatches,
+        id: &str,
+    ) -> impl Iterator<Item = (usize, &'a T)> {
+        matches
+            .indices_of(id)
+            .into_iter()
+            .flatten()
+            .zip(matches.get_many::<T>(id).into_iter().flatten())
+    }
+
+    let mut triggers = indexed::<String>(matches, "on")
+        .map(|(_, pattern)| TriggerArgs {
+            pattern,
+            response: None,
+            repeat: false,
+            cooldown: None,
+        })
+        .collect::<Vec<_>>();
+    let on_indices = indexed::<String>(matches, "on")
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let owner = |idx: usize, arg: &str| {
+        on_indices
+            .iter()
+            .rposition(|&x| x < idx)
+            .with_context(|| format!("{} must follow --on", arg))
+    };
+
+    for (idx, text) in indexed::<String>(matches, "send") {
+        triggers[owner(idx, "--send")?].response = Some(unescape(text)?);
+    }
+    for (idx, path) in indexed::<PathBuf>(matches, "send_file") {
+        triggers[owner(idx, "--send-file")?].response =
+            Some(std::fs::read(path).with_context(|| format!("cannot read '{}'", path.display()))?);
+    }
+    for (idx, _) in indexed::<bool>(matches, "repeat") {
+        triggers[owner(idx, "--repeat")?].repeat = true;
+    }
+    for (idx, ms) in indexed::<u64>(matches, "cooldown") {
+        triggers[owner(idx, "--cooldown")?].cooldown = Some(*ms);
+    }
+
+    triggers
+        .into_iter()
+        .map(|args| {
+            let response = args
+                .response
+                .with_context(|| format!("--on '{}' needs --send or --send-file", args.pattern))?;
+            let mut trigger = Trigger::new(args.pattern, response)
+                .with_context(|| format!("invalid pattern '{}'", args.pattern))?
+                .repeat(args.repeat);
+            if let Some(ms) = args.cooldown {
+                trigger = trigger.cooldown(Duration::from_millis(ms));
+            }
+            Ok(trigger)
+        })
+        .collect()
+}
+
+/// Parses a `--rlimit` value of the form `NAME=SOFT[:HARD]`.
+///
+/// Without a hard limit both limits are set to the same value.
+fn parse_rlimit(value: &str) -> Result<(ResourceLimit, Option<u64>, Option<u64>), Error> {
+    let (name, limits) = value
+        .split_once('=')
+        .with_context(|| format!("invalid resource limit '{}'", value))?;
+    let limit = match name {
+        "cpu" => ResourceLimit::CpuTime,
+        "as" => ResourceLimit::AddressSpace,
+        "nofile" => ResourceLimit::OpenFiles,
+        "core" => ResourceLimit::CoreSize,
+        _ => anyhow::bail!("unknown resource limit '{}'", name),
+    };
+    let parse = |x: &str| match x {
+        "unlimited" => Ok(None),
+        _ => x
+            .parse()
+            .map(Some)
+            .with_context(|| format!("invalid resource limit '{}'", value)),
+    };
+    let (soft, hard) = match limits.split_once(':') {
+        Some((soft, hard)) => (parse(soft)?, parse(hard)?),
+        None => (parse(limits)?, parse(limits)?),
+    };
+    Ok((limit, soft, hard))
+}
+
+/// Resolves backslash escapes (`\r`, `\n`, `\t`, `\e`, `\0`, `\xNN` and `\\`).
+fn unescape(s: &str) -> Result<Vec<u8>, Error> {
+    let mut rv = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(c) = bytes.next() {
+        if c != b'\\' {
+            rv.push(c);
+            continue;
+        }
+        rv.push(match bytes.next() {
+            Some(b'r') => b'\r',
+            Some(b'n') => b'\n',
+            Some(b't') => b'\t',
+            Some(b'e') => 0x1b,
+            Some(b'0') => 0,
+            Some(b'\\') => b'\\',
+            Some(b'x') => {
+                let hex = [bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|x| u8::from_str_radix(x, 16).ok())
+                    .with_context(|| format!("invalid escape in '{}'", s))?
+            }
+            _ => anyhow::bail!("invalid escape in '{}'", s),
+        });
+    }
+    Ok(rv)
+}
+
 fn make_app() -> Command {
     Command::new("teetty")
-        .override_usage("teetty [OPTIONS] -- [COMMAND ...]")
+        .override_usage(
+            "teetty [OPTIONS] -- [COMMAND ...]\n       \
+             teetty multi [OPTIONS] -n NAME:COMMAND [-n NAME:COMMAND ...]",
+        )
         .max_term_width(92)
         .about(
             "teetty is a wrapper binary to execute a command in a pty with \
             remote control facilities.",
         )
+        .subcommand(multi::make_command())
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .disable_help_subcommand(true)
         .arg(
             Arg::new("command")
                 .help("The command and the arguments to run")
                 .num_args(1..)
                 .value_name("COMMAND")
                 .value_parser(clap::builder::OsStringValueParser::new())
-                .required_unless_present_any(["help", "version"])
+                .required_unless_present_any(["help", "version", "reset_terminal"])
                 .last(true),
         )
         .arg(
             Arg::new("in_path")
                 .help(
                     "A path to a FIFO or file.  When provided it's contents are \
-                    monitored and sent to the terminal as input",
+                    monitored and sent to the terminal as input.  Can be provided \
+                    multiple times",
                 )
                 .short('i')
                 .long("in")
                 .value_name("PATH")
+                .action(ArgAction::Append)
                 .value_parser(clap::builder::PathBufValueParser::new()),
         )
+        .arg(
+            Arg::new("in_buffer")
+                .help(
+                    "The number of bytes of input buffered for stdin and each input \
+                     file while the program is not reading [default: 262144]",
+                )
+                .long("in-buffer")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("in_overflow")
+                .help(
+                    "What to do when the input buffer is full.  `block` stops reading \
+                     until the program caught up, `drop` discards the input and \
+                     `error` aborts and hangs up the program",
+                )
+                .long("in-overflow")
+                .value_name("POLICY")
+                .default_value("block")
+                .value_parser(["block", "drop", "error"]),
+        )
         .arg(
             Arg::new("out_path")
                 .help(
                     "Path to an optional output file.  stdout and stderr are \
                      captured and streamed into this file in addition to the \
-                     terminal output",
+                     terminal output.  Instead of a path `unix:/path` or \
+                     `tcp:HOST:PORT` can be used to stream into a socket.  Can be \
+                     provided multiple times",
                 )
                 .short('o')
                 .long("out")
                 .value_name("PATH")
+                .action(ArgAction::Append)
+                .value_parser(clap::builder::OsStringValueParser::new()),
+        )
+        .arg(
+            Arg::new("out_buffer")
+                .help(
+                    "The number of bytes buffered for each FIFO or socket output \
+                     that cannot keep up [default: 262144]",
+                )
+                .long("out-buffer")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("out_overflow")
+                .help(
+                    "What to do when the buffer of a FIFO or socket output is full.  \
+                     `block` also blocks the terminal until the receiver caught up",
+                )
+                .long("out-overflow")
+                .value_name("POLICY")
+                .default_value("drop-oldest")
+                .value_parser(["block", "drop-oldest", "drop-newest"]),
+        )
+        .arg(
+            Arg::new("protect_noecho")
+                .help(
+                    "Keeps input out of the input log while the program disabled echo, \
+                     for instance when asking for a password.  An `[input hidden]` \
+                     marker is logged instead",
+                )
+                .long("protect-noecho")
+                .requires("in_log_path")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("on")
+                .help(
+                    "A regular expression that is matched against the output.  When it \
+                     matches, the input of the following --send or --send-file is sent \
+                     to the terminal.  Can be provided multiple times",
+                )
+                .long("on")
+                .value_name("PATTERN")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("send")
+                .help(
+                    "The input to send when the preceding --on pattern matches.  \
+                     Supports the escapes \\r, \\n, \\t, \\e, \\0, \\xNN and \\\\",
+                )
+                .long("send")
+                .value_name("TEXT")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("send_file")
+                .help("Like --send but sends the contents of the given file")
+                .long("send-file")
+                .value_name("PATH")
+                .action(ArgAction::Append)
+                .value_parser(clap::builder::PathBufValueParser::new()),
+        )
+        .arg(
+            Arg::new("repeat")
+                .help(
+                    "Lets the preceding --on pattern fire every time it matches \
+                     instead of only once",
+                )
+                .long("repeat")
+                .num_args(0)
+                .default_missing_value("true")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(bool)),
+        )
+        .arg(
+            Arg::new("cooldown")
+                .help(
+                    "Ignores matches of the preceding --on pattern for the given number \
+                     of milliseconds after it fired.  Implies --repeat",
+                )
+                .long("cooldown")
+                .value_name("MS")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("child_exit")
+                .help(
+                    "What happens when the process exits while background processes \
+                     it left behind still hold the terminal: 'wait' keeps going until \
+                     they are gone, 'exit' exits after draining the remaining output \
+                     briefly and 'kill' also kills the remaining processes",
+                )
+                .long("child-exit")
+                .value_name("POLICY")
+                .value_parser(["wait", "exit", "kill"])
+                .default_value("wait"),
+        )
+        .arg(
+            Arg::new("stdin_eof")
+                .help(
+                    "What happens when stdin reaches end of file: 'eof' sends end of \
+                     file to the process, 'ignore' keeps it running fed only from the \
+                     --in paths, 'hangup' sends SIGHUP and 'wait' sends end of file once \
+                     nobody writes into the --in paths anymore",
+                )
+                .long("stdin-eof")
+                .value_name("POLICY")
+                .value_parser(["eof", "ignore", "hangup", "wait"])
+                .default_value("eof"),
+        )
+        .arg(
+            Arg::new("in_log_path")
+                .help(
+                    "Path to an optional input log file.  Everything typed on stdin \
+                     and sent in via the --in path is recorded in this file",
+                )
+                .long("in-log")
+                .value_name("PATH")
                 .value_parser(clap::builder::PathBufValueParser::new()),
         )
+        .arg(
+            Arg::new("in_log_tagged")
+                .help(
+                    "Writes every chunk of input into the input log as a separate \
+                     line with timestamp and source (`stdin`, the path of the input \
+                     file or `trigger`)",
+                )
+                .long("in-log-tagged")
+                .requires("in_log_path")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("events_path")
+                .help(
+                    "Path to an optional events log.  Session events such as changes \
+                     of the foreground process or the window title are appended to it \
+                     as JSON lines",
+                )
+                .long("events")
+                .value_name("PATH")
+                .value_parser(clap::builder::PathBufValueParser::new()),
+        )
+        .arg(
+            Arg::new("status_path")
+                .help(
+                    "Path to an optional status file.  It's kept up to date with the \
+                     current state of the session (such as the foreground process, \
+                     window title and working directory) as JSON",
+                )
+                .long("status")
+                .value_name("PATH")
+                .value_parser(clap::builder::PathBufValueParser::new()),
+        )
+        .arg(
+            Arg::new("strip_osc")
+                .help(
+                    "Strips window title and working directory sequences (OSC 0, 1, 2 \
+                     and 7) from the output files",
+                )
+                .long("strip-osc")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("pass_fd")
+                .help(
+                    "Passes an open file descriptor to the process under the same \
+                     number.  All other file descriptors besides stdin, stdout and \
+                     stderr are closed.  Can be provided multiple times",
+                )
+                .long("pass-fd")
+                .value_name("FD")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(i32).range(0..)),
+        )
+        .arg(
+            Arg::new("rlimit")
+                .help(
+                    "Sets a resource limit for the process as NAME=SOFT[:HARD] where \
+                     NAME is one of cpu (seconds), as (bytes), nofile or core (bytes).  \
+                     A limit can be 'unlimited'.  Can be provided multiple times",
+                )
+                .long("rlimit")
+                .value_name("LIMIT")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("umask")
+                .help("Sets the umask (in octal) of the process")
+                .long("umask")
+                .value_name("MODE"),
+        )
+        .arg(
+            Arg::new("nice")
+                .help("Sets the nice value of the process")
+                .long("nice")
+                .value_name("N")
+                .allow_negative_numbers(true)
+                .value_parser(clap::value_parser!(i32)),
+        )
+        .arg(
+            Arg::new("uid")
+                .help("Runs the process as the given user ID")
+                .long("uid")
+                .value_name("UID")
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("gid")
+                .help("Runs the process with the given group ID")
+                .long("gid")
+                .value_name("GID")
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("groups")
+                .help(
+                    "Sets the supplementary groups of the process as comma separated \
+                     group IDs.  When switching users as root they are cleared otherwise",
+                )
+                .long("groups")
+                .value_name("GIDS")
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("log_header")
+                .help(
+                    "Writes a header (command, start time, pid, working directory, \
+                     terminal size and version) and a footer (exit status and duration) \
+                     around the run into the output files.  This helps to tell runs \
+                     apart when appending to a log",
+                )
+                .long("log-header")
+                .value_name("FORMAT")
+                .value_parser(["comment", "json"]),
+        )
+        .arg(
+            Arg::new("redact")
+                .help(
+                    "A regular expression for secrets that are replaced with [REDACTED] \
+                     in the output and input logs.  The terminal output is not changed.  \
+                     Can be provided multiple times",
+                )
+                .long("redact")
+                .value_name("REGEX")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("redact_env")
+                .help(
+                    "Redacts the value of the given environment variable like --redact.  \
+                     Can be provided multiple times",
+                )
+                .long("redact-env")
+                .value_name("VAR")
+                .action(ArgAction::Append),
+        )
         .arg(
             Arg::new("truncate_out")
-                .help("When this flag is set the output file is truncated first")
+                .help(
+                    "When this flag is set the output, input log and events files are \
+                     truncated first",
+                )
                 .long("truncate")
                 .action(ArgAction::SetTrue),
         )
@@ -101,6 +681,26 @@ fn make_app() -> Command {
                 .aliases(["disable-flush"])
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("flush_interval")
+                .help(
+                    "When flushing is disabled, flushes the output and input log \
+                     files at least every given number of milliseconds",
+                )
+                .long("flush-interval")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("fsync")
+                .help(
+                    "Controls if log files are synced to disk.  Can be `never` (the \
+                     default), `always` to sync after every flush or a number of \
+                     milliseconds to sync at most once in that interval",
+                )
+                .long("fsync")
+                .value_name("POLICY"),
+        )
         .arg(
             Arg::new("no_echo")
                 .help("Disables echoing of inputs")
@@ -129,6 +729,15 @@ fn make_app() -> Command {
                 .aliases(["disable-raw"])
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("reset_terminal")
+                .help(
+                    "Restores sane settings of the terminal, such as after a process \
+                     was killed and left it in raw mode or on the alternate screen",
+                )
+                .long("reset-terminal")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("version")
                 .help("Prints version info")
@@ -0,0 +1,328 @@
+//! Runs several commands with prefixed, multiplexed output.
+//!
+//! Every command runs in its own pty.  Their output is merged line by line
+//! with the name of the command in front, similar to foreman.  Input can be
+//! routed to one of the commands.
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Error};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+
+use tty_spawn::{TtyChild, TtySpawn};
+
+/// The colors of the prefixes, assigned in turn.
+const COLORS: [u8; 6] = [36, 33, 32, 35, 34, 31];
+
+/// How often the processes are checked for having exited and the terminal
+/// for having been resized.
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a partial line is held back waiting for the rest of it.
+const PARTIAL_LINE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long output is drained after a process exited while something else
+/// still holds its pty open.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long stopped processes get to exit after the hangup before they are
+/// killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Controls when `teetty multi` stops.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ExitPolicy {
+    /// Waits for all processes to exit.
+    All,
+    /// Stops the others once the first process exited.
+    First,
+    /// Stops the others once a process failed.
+    Failure,
+}
+
+enum Message {
+    Output(usize, Vec<u8>),
+    Closed(usize),
+}
+
+struct Process {
+    prefix: String,
+    child: TtyChild,
+    /// Output after the last newline.
+    partial: Vec<u8>,
+    partial_since: Option<Instant>,
+    exited_at: Option<Instant>,
+    status: i32,
+    closed: bool,
+    reported: bool,
+}
+
+impl Process {
+    fn is_done(&self) -> bool {
+        self.exited_at
+            .map_or(false, |x| self.closed || x.elapsed() >= DRAIN_TIMEOUT)
+    }
+
+    /// Writes all complete lines of the output.
+    fn write_lines(&mut self, out: &mut impl Write, buf: &[u8]) -> Result<(), io::Error> {
+        self.partial.extend_from_slice(buf);
+        if let Some(end) = self.partial.iter().rposition(|&x| x == b'\n') {
+            let rest = self.partial.split_off(end + 1);
+            let complete = mem::replace(&mut self.partial, rest);
+            for line in complete[..end].split(|&x| x == b'\n') {
+                self.write_line(out, line)?;
+            }
+        }
+        self.partial_since = if self.partial.is_empty() {
+            None
+        } else {
+            self.partial_since.or_else(|| Some(Instant::now()))
+        };
+        Ok(())
+    }
+
+    /// Writes out a partial line that was held back too long.
+    fn write_partial(&mut self, out: &mut impl Write, force: bool) -> Result<(), io::Error> {
+        if self
+            .partial_since
+            .map_or(false, |x| force || x.elapsed() >= PARTIAL_LINE_TIMEOUT)
+        {
+            let line = mem::take(&mut self.partial);
+            self.write_line(out, &line)?;
+            self.partial_since = None;
+        }
+        Ok(())
+    }
+
+    fn write_line(&self, out: &mut impl Write, line: &[u8]) -> Result<(), io::Error> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        out.write_all(self.prefix.as_bytes())?;
+        out.write_all(line)?;
+        out.write_all(b"\n")
+    }
+}
+
+pub fn execute(matches: &ArgMatches) -> Result<i32, Error> {
+    let policy = match matches.get_one::<String>("exit").unwrap().as_str() {
+        "all" => ExitPolicy::All,
+        "failure" => ExitPolicy::Failure,
+        _ => ExitPolicy::First,
+    };
+    let mut terminal_size = tty_spawn::terminal_size();
+    let color = terminal_size.is_some() && !matches.get_flag("no_color");
+
+    let mut commands = Vec::<(&str, &str)>::new();
+    for value in matches.get_many::<String>("process").unwrap_or_default() {
+        let (name, command) = value
+            .split_once(':')
+            .filter(|(name, command)| !name.is_empty() && !command.is_empty())
+            .with_context(|| format!("invalid process '{}', expected NAME:COMMAND", value))?;
+        if commands.iter().any(|x| x.0 == name) {
+            bail!("duplicate process name '{}'", name);
+        }
+        commands.push((name, command));
+    }
+    let input = match matches.get_one::<String>("input") {
+        Some(name) => Some(
+            commands
+                .iter()
+                .position(|x| x.0 == name)
+                .with_context(|| format!("unknown process '{}'", name))?,
+        ),
+        None => None,
+    };
+
+    let width = commands
+        .iter()
+        .map(|x| x.0.chars().count())
+        .max()
+        .unwrap_or(0);
+    // the prefix takes up some of the line
+    let child_size =
+        |(cols, rows): (u16, u16)| (cols.saturating_sub(width as u16 + 3).max(20), rows);
+    let (sender, receiver) = channel();
+    let mut processes = Vec::new();
+    for (idx, (name, command)) in commands.iter().enumerate() {
+        let mut child = TtySpawn::new_cmdline(["sh", "-c", command].iter())
+            .echo(false)
+            .spawn_child()
+            .with_context(|| format!("cannot run '{}'", name))?;
+        if let Some((cols, rows)) = terminal_size.map(child_size) {
+            child.resize(cols, rows)?;
+        }
+        spawn_reader(idx, child.stdout.take().unwrap(), sender.clone());
+        let prefix = format!("{:<width$} | ", name, width = width);
+        processes.push(Process {
+            prefix: if color {
+                format!("\x1b[{}m{}\x1b[0m", COLORS[idx % COLORS.len()], prefix)
+            } else {
+                prefix
+            },
+            child,
+            partial: Vec::new(),
+            partial_since: None,
+            exited_at: None,
+            status: 0,
+            closed: false,
+            reported: false,
+        });
+    }
+    drop(sender);
+    if let Some(idx) = input {
+        spawn_input(processes[idx].child.stdin.take().unwrap());
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut code = None;
+    let mut stopped_at = None::<Instant>;
+    while !processes.iter().all(|x| x.reported) {
+        match receiver.recv_timeout(CHECK_INTERVAL) {
+            Ok(Message::Output(idx, buf)) => processes[idx].write_lines(&mut out, &buf)?,
+            Ok(Message::Closed(idx)) => processes[idx].closed = true,
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {}
+        }
+        for process in processes.iter_mut() {
+            process.write_partial(&mut out, process.closed)?;
+        }
+
+        let mut stop = false;
+        for process in processes.iter_mut().filter(|x| x.exited_at.is_none()) {
+            if let Some(status) = process.child.try_wait()? {
+                process.exited_at = Some(Instant::now());
+                process.status = status;
+                if code.map_or(true, |x| x == 0) && stopped_at.is_none() {
+                    code = Some(status);
+                }
+                stop |= match policy {
+                    ExitPolicy::All => false,
+                    ExitPolicy::First => true,
+                    ExitPolicy::Failure => status != 0,
+                };
+            }
+        }
+        for process in processes.iter_mut().filter(|x| !x.reported && x.is_done()) {
+            process.write_partial(&mut out, true)?;
+            let status = format!("[exited with code {}]", process.status);
+            process.write_line(&mut out, status.as_bytes())?;
+            process.reported = true;
+        }
+        out.flush()?;
+
+        let new_size = tty_spawn::terminal_size();
+        if new_size != terminal_size {
+            terminal_size = new_size;
+            if let Some((cols, rows)) = terminal_size.map(child_size) {
+                for process in processes.iter().filter(|x| x.exited_at.is_none()) {
+                    process.child.resize(cols, rows).ok();
+                }
+            }
+        }
+
+        if stop && stopped_at.is_none() {
+            stopped_at = Some(Instant::now());
+            for process in processes.iter().filter(|x| x.exited_at.is_none()) {
+                process.child.hangup();
+            }
+        } else if stopped_at.map_or(false, |x| x.elapsed() >= STOP_TIMEOUT) {
+            for process in processes.iter().filter(|x| x.exited_at.is_none()) {
+                process.child.kill();
+            }
+        }
+    }
+
+    Ok(code.unwrap_or(0))
+}
+
+/// Reads the output of a process on a separate thread.
+fn spawn_reader(idx: usize, mut reader: impl Read + Send + 'static, sender: Sender<Message>) {
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender
+                        .send(Message::Output(idx, buf[..n].to_vec()))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        sender.send(Message::Closed(idx)).ok();
+    });
+}
+
+/// Forwards stdin to a process on a separate thread.
+///
+/// At the end of stdin, end of file (`^D`) is sent.
+fn spawn_input(mut writer: impl Write + Send + 'static) {
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        let mut stdin = io::stdin();
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if writer.write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        writer.write_all(b"\x04").ok();
+    });
+}
+
+pub fn make_command() -> Command {
+    Command::new("multi")
+        .about(
+            "Runs several commands, each in its own pty, and merges their output \
+             line by line with the name of the command in front.",
+        )
+        .override_usage("teetty multi [OPTIONS] -n NAME:COMMAND [-n NAME:COMMAND ...]")
+        .arg(
+            Arg::new("process")
+                .help(
+                    "A command to run with the name in front of its output.  The \
+                     command is run with `sh -c`.  Can be provided multiple times",
+                )
+                .short('n')
+                .long("process")
+                .value_name("NAME:COMMAND")
+                .action(ArgAction::Append)
+                .required(true),
+        )
+        .arg(
+            Arg::new("input")
+                .help("The name of the process that receives stdin")
+                .long("input")
+                .value_name("NAME"),
+        )
+        .arg(
+            Arg::new("exit")
+                .help(
+                    "When to stop.  `first` stops all processes once one exited, \
+                     `failure` once one failed and `all` waits for all of them",
+                )
+                .long("exit")
+                .value_name("POLICY")
+                .default_value("first")
+                .value_parser(["all", "first", "failure"]),
+        )
+        .arg(
+            Arg::new("no_color")
+                .help("Disables the colors of the names")
+                .long("no-color")
+                .action(ArgAction::SetTrue),
+        )
+}
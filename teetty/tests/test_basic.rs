@@ -1,5 +1,9 @@
+use std::ffi::OsString;
 use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::net::UnixListener;
 use std::process::{Command, Stdio};
+use std::thread;
 
 #[test]
 fn test_basic() {
@@ -137,3 +141,544 @@ fn test_pager() {
         .unwrap();
     assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "cat");
 }
+
+#[test]
+fn test_multiple_outputs() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let out1 = tempdir.path().join("out1");
+    let out2 = tempdir.path().join("out2");
+    fs::write(&out2, "before\n").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&out1)
+        .arg("--out")
+        .arg(&out2)
+        .arg("--no-echo")
+        .arg("--")
+        .arg("tests/basic.sh")
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    let out1 = fs::read_to_string(&out1).unwrap();
+    let out2 = fs::read_to_string(&out2).unwrap();
+
+    assert_eq!(status.code(), Some(42));
+    assert_eq!(format!("before\n{}", out1), out2);
+    insta::assert_snapshot!(&out1, @r###"
+    stdout output
+    stderr output
+    stdin: tty
+    stdout: tty
+    stderr: tty
+    "###);
+}
+
+#[test]
+fn test_unix_socket_output() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let sock = tempdir.path().join("sock");
+    let listener = UnixListener::bind(&sock).unwrap();
+    let receiver = thread::spawn(move || {
+        let mut rv = String::new();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_string(&mut rv)
+            .unwrap();
+        rv
+    });
+
+    let mut target = OsString::from("unix:");
+    target.push(&sock);
+    let status = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&target)
+        .arg("--no-echo")
+        .arg("--")
+        .arg("tests/basic.sh")
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(42));
+    insta::assert_snapshot!(receiver.join().unwrap(), @r###"
+    stdout output
+    stderr output
+    stdin: tty
+    stdout: tty
+    stderr: tty
+    "###);
+}
+
+#[test]
+fn test_fifo_output_without_reader() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let fifo = tempdir.path().join("fifo");
+    assert!(Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .unwrap()
+        .success());
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&fifo)
+        .arg("--no-echo")
+        .arg("--")
+        .arg("tests/basic.sh")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(42));
+    insta::assert_snapshot!(String::from_utf8(output.stdout).unwrap(), @r###"
+    stdout output
+    stderr output
+    stdin: tty
+    stdout: tty
+    stderr: tty
+    "###);
+}
+
+#[test]
+fn test_no_flush_fsync() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let out = tempdir.path().join("out");
+    let status = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&out)
+        .arg("--no-flush")
+        .arg("--flush-interval")
+        .arg("10")
+        .arg("--fsync")
+        .arg("always")
+        .arg("--no-echo")
+        .arg("--")
+        .arg("tests/basic.sh")
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(42));
+    insta::assert_snapshot!(fs::read_to_string(&out).unwrap(), @r###"
+    stdout output
+    stderr output
+    stdin: tty
+    stdout: tty
+    stderr: tty
+    "###);
+}
+
+#[test]
+fn test_redact() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let out = tempdir.path().join("out");
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&out)
+        .arg("--script-mode")
+        .arg("--redact")
+        .arg("tok-[0-9a-f]+")
+        .arg("--redact-env")
+        .arg("TEETTY_TEST_SECRET")
+        .env("TEETTY_TEST_SECRET", "hunter2")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("printf 'token: tok-12'; sleep 0.05; printf '34ab\\n'; echo \"pw: $TEETTY_TEST_SECRET\"")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "token: tok-1234ab\npw: hunter2\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "token: [REDACTED]\npw: [REDACTED]\n"
+    );
+}
+
+#[test]
+fn test_events_and_status() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let events = tempdir.path().join("events");
+    let status_path = tempdir.path().join("status");
+    let status = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--events")
+        .arg(&events)
+        .arg("--status")
+        .arg(&status_path)
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("sleep 0.3; exit 3")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(3));
+
+    let events = fs::read_to_string(&events).unwrap();
+    let lines = events.lines().collect::<Vec<_>>();
+    assert!(lines[0].contains(r#""event":"foreground","process":{"pid":"#));
+    // the first event might still see teetty itself before the exec
+    if cfg!(target_os = "linux") {
+        assert!(lines.iter().any(|x| x.contains(r#""name":"sh""#)));
+    }
+    assert!(lines
+        .last()
+        .unwrap()
+        .ends_with(r#""event":"exit","exit_code":3}"#));
+
+    let status = fs::read_to_string(&status_path).unwrap();
+    assert!(status.starts_with(r#"{"pid":"#));
+    assert!(status.ends_with(",\"exit_code\":3}\n"));
+}
+
+#[test]
+fn test_osc_metadata() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let out = tempdir.path().join("out");
+    let events = tempdir.path().join("events");
+    let status_path = tempdir.path().join("status");
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&out)
+        .arg("--events")
+        .arg(&events)
+        .arg("--status")
+        .arg(&status_path)
+        .arg("--strip-osc")
+        .arg("--script-mode")
+        .arg("--")
+        .arg("printf")
+        .arg(r"\033]2;my title\007hello\033]7;file://host/tmp/a%%20b\033\\ world\033[0m\n")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "\x1b]2;my title\x07hello\x1b]7;file://host/tmp/a%20b\x1b\\ world\x1b[0m\n"
+    );
+    assert_eq!(fs::read_to_string(&out).unwrap(), "hello world\x1b[0m\n");
+
+    let events = fs::read_to_string(&events).unwrap();
+    assert!(events.contains(r#""event":"title","title":"my title"}"#));
+    assert!(events.contains(r#""event":"cwd","cwd":"/tmp/a b"}"#));
+    let status = fs::read_to_string(&status_path).unwrap();
+    assert!(status.contains(r#""title":"my title","cwd":"/tmp/a b","exit_code":0}"#));
+}
+
+#[test]
+fn test_log_header() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let out = tempdir.path().join("out");
+    fs::write(&out, "before\n").unwrap();
+    for (format, cmd) in [("json", "printf 'one'; exit 2"), ("comment", "echo two")] {
+        Command::new(env!("CARGO_BIN_EXE_teetty"))
+            .arg("--out")
+            .arg(&out)
+            .arg("--log-header")
+            .arg(format)
+            .arg("--script-mode")
+            .arg("--")
+            .arg("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+    }
+
+    let out = fs::read_to_string(&out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "before");
+    assert!(lines[1].starts_with(
+        r#"{"event":"session_start","command":["sh","-c","printf 'one'; exit 2"],"started":""#
+    ));
+    assert!(lines[1].contains(r#","pid":"#));
+    assert!(lines[1].contains(&format!(r#""version":"{}"}}"#, env!("CARGO_PKG_VERSION"))));
+    assert_eq!(lines[2], "one");
+    assert!(lines[3].starts_with(r#"{"event":"session_end","exit_code":2,"duration":"#));
+    assert_eq!(lines[4], "# session start");
+    assert_eq!(lines[5], "# command: sh -c echo two");
+    assert!(lines[6].starts_with("# started: "));
+    assert!(lines[7].starts_with("# pid: "));
+    assert!(lines[8].starts_with("# cwd: "));
+    assert_eq!(lines[9], "# size: 0x0");
+    assert_eq!(lines[11], "two");
+    assert_eq!(lines[12], "# session end");
+    assert_eq!(lines[13], "# exit status: 0");
+    assert!(lines[14].starts_with("# duration: "));
+}
+
+#[test]
+fn test_fds() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let out = tempdir.path().join("out");
+    let passed = tempdir.path().join("passed");
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&out)
+        .arg("--pass-fd")
+        .arg("2")
+        .arg("--no-echo")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("for fd in 3 4 5 6 7 8 9; do (: >&$fd) 2>/dev/null && echo open $fd; done; echo passed >&2")
+        .stdin(Stdio::null())
+        .stderr(File::create(&passed).unwrap())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(fs::read_to_string(&passed).unwrap(), "passed\n");
+
+    // a file descriptor that is not open must not pass on our own output file
+    let out = tempdir.path().join("out2");
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&out)
+        .arg("--pass-fd")
+        .arg("3")
+        .arg("--")
+        .arg("true")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("cannot pass file descriptor 3"));
+    assert!(!out.exists());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--")
+        .arg("./does-not-exist")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("No such file or directory"));
+}
+
+#[test]
+fn test_process_settings() {
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--rlimit")
+        .arg("nofile=64")
+        .arg("--rlimit")
+        .arg("core=0:0")
+        .arg("--umask")
+        .arg("027")
+        .arg("--nice")
+        .arg("19")
+        .arg("--script-mode")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("ulimit -n; ulimit -c; ulimit -Hc; umask; nice")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    insta::assert_snapshot!(String::from_utf8(output.stdout).unwrap(), @r###"
+    64
+    0
+    0
+    0027
+    19
+    "###);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--rlimit")
+        .arg("nofile=10:5")
+        .arg("--")
+        .arg("true")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+
+    let is_root = Command::new("id").arg("-u").output().unwrap().stdout == b"0\n";
+    if is_root {
+        let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+            .arg("--uid")
+            .arg("65534")
+            .arg("--gid")
+            .arg("65534")
+            .arg("--groups")
+            .arg("65534")
+            .arg("--script-mode")
+            .arg("--")
+            .arg("sh")
+            .arg("-c")
+            .arg("id -u; id -g; id -G")
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "65534\n65534\n65534\n"
+        );
+    }
+}
+
+#[test]
+fn test_restore_terminal() {
+    // the inner teetty runs in the pty of the outer one, so it switches it to
+    // raw mode and needs to restore it even when it's killed.
+    let teetty = env!("CARGO_BIN_EXE_teetty");
+    let script = format!(
+        "{} -- sh -c 'printf \"\\033[?1049h\\033[?25lhi\\033[?1000;1006h\\033[?1000l\"; kill -TERM $PPID'; \
+         stty -a | grep -o ' -\\?icanon'; {} --reset-terminal",
+        teetty, teetty
+    );
+    let output = Command::new(teetty)
+        .arg("--no-echo")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let inner = "\x1b[?1049h\x1b[?25lhi\x1b[?1000;1006h\x1b[?1000l\x1b[?1049l\x1b[?25h\x1b[?1006l";
+    assert!(stdout.starts_with(inner));
+    let rest = &stdout[inner.len()..];
+    assert!(rest.contains(" icanon"));
+    assert!(rest.ends_with("\x1b[?1015l"));
+}
+
+#[test]
+fn test_job_control() {
+    // the inner teetty runs in the pty of the outer one which lets us look at
+    // the terminal settings while it's suspended and resumed.
+    let script = format!(
+        "{} -- sleep 3 </dev/tty &
+        p=$!; sleep 0.5
+        stty -a | grep -o ' -\\?icanon'
+        kill -TSTP $p; sleep 0.3
+        stty -a | grep -o ' -\\?icanon'
+        ps -o stat= -p $p | cut -c1
+        kill -CONT $p; sleep 0.3
+        stty -a | grep -o ' -\\?icanon'
+        ps -o stat= -p $p | cut -c1
+        kill $p; wait",
+        env!("CARGO_BIN_EXE_teetty")
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--no-echo")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    insta::assert_snapshot!(String::from_utf8(output.stdout).unwrap().replace('\r', ""), @r###"
+     -icanon
+     icanon
+    T
+     -icanon
+    S
+    "###);
+}
+
+#[test]
+fn test_window_size() {
+    // the inner teetty runs in the pty of the outer one, so resizing that pty
+    // and sending SIGWINCH needs to wake it up to forward the new size.
+    let script = format!(
+        "stty cols 50 rows 20
+        {} --no-echo -- sh -c 'trap \"stty size; exit\" WINCH; stty size; while :; do sleep 0.1; done' </dev/tty &
+        p=$!; sleep 0.5
+        stty cols 100 rows 30
+        kill -WINCH $p; wait",
+        env!("CARGO_BIN_EXE_teetty")
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--no-echo")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    insta::assert_snapshot!(String::from_utf8(output.stdout).unwrap().replace('\r', ""), @r###"
+    20 50
+    30 100
+    "###);
+}
+
+#[test]
+fn test_child_exit() {
+    for policy in ["exit", "kill"] {
+        let started = std::time::Instant::now();
+        let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+            .arg("--child-exit")
+            .arg(policy)
+            .arg("--script-mode")
+            .arg("--")
+            .arg("sh")
+            .arg("-c")
+            .arg("(trap '' HUP; sleep 5) & echo started; exit 4")
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(4));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "started\n");
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+    }
+}
+
+#[test]
+fn test_large_output() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let out = tempdir.path().join("out");
+    let script = "i=0; while [ $i -lt 2000 ]; do echo \"line $i\"; i=$((i+1)); done; \
+                  head -c 3000000 /dev/zero | tr '\\0' x; echo";
+    let expected = (0..2000)
+        .map(|i| format!("line {}\r\n", i))
+        .chain(Some(format!("{}\r\n", "x".repeat(3000000))))
+        .collect::<String>();
+
+    // stdout is a pipe and nothing looks at the output
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stdout).unwrap() == expected);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--out")
+        .arg(&out)
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stdout).unwrap() == expected);
+    assert!(fs::read_to_string(&out).unwrap() == expected);
+}
@@ -0,0 +1,164 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_multi() {
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("multi")
+        .arg("-n")
+        .arg("web:echo one; sleep 0.2; printf 'two\\nthree'")
+        .arg("-n")
+        .arg("worker:echo four; exit 3")
+        .arg("--exit")
+        .arg("all")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(3));
+    let web = stdout
+        .lines()
+        .filter(|x| x.starts_with("web    | "))
+        .collect::<Vec<_>>();
+    let worker = stdout
+        .lines()
+        .filter(|x| x.starts_with("worker | "))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        web,
+        [
+            "web    | one",
+            "web    | two",
+            "web    | three",
+            "web    | [exited with code 0]"
+        ]
+    );
+    assert_eq!(worker, ["worker | four", "worker | [exited with code 3]"]);
+    assert_eq!(stdout.lines().count(), 6);
+}
+
+#[test]
+fn test_multi_exit_policies() {
+    let run = |policy: &str| {
+        let started = Instant::now();
+        let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+            .arg("multi")
+            .arg("-n")
+            .arg("ok:true")
+            .arg("-n")
+            .arg("bad:sleep 0.5; exit 2")
+            .arg("-n")
+            .arg("slow:sleep 10")
+            .arg("--exit")
+            .arg(policy)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        (output.status.code(), stdout, started.elapsed())
+    };
+
+    // the first process that exits stops the others
+    let (code, stdout, elapsed) = run("first");
+    assert_eq!(code, Some(0));
+    assert!(elapsed < Duration::from_secs(5));
+    assert!(stdout.contains("ok   | [exited with code 0]"));
+    assert!(!stdout.contains("[exited with code 2]"));
+
+    // only the failure stops the others
+    let (code, stdout, elapsed) = run("failure");
+    assert_eq!(code, Some(2));
+    assert!(elapsed >= Duration::from_millis(500));
+    assert!(elapsed < Duration::from_secs(5));
+    assert!(stdout.contains("bad  | [exited with code 2]"));
+    assert!(stdout.contains("slow | [exited with code 129]"));
+}
+
+#[test]
+fn test_multi_input() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("multi")
+        .arg("--input")
+        .arg("cat")
+        .arg("-n")
+        .arg("cat:cat")
+        .arg("-n")
+        .arg("other:cat")
+        .arg("--exit")
+        .arg("first")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"hello\nworld\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout
+            .lines()
+            .filter(|x| x.starts_with("cat"))
+            .collect::<Vec<_>>(),
+        [
+            "cat   | hello",
+            "cat   | world",
+            "cat   | [exited with code 0]"
+        ]
+    );
+    assert!(stdout.contains("other | [exited with code 129]"));
+}
+
+#[test]
+fn test_multi_resize() {
+    // teetty multi runs in the pty of an outer teetty, so resizing that pty
+    // needs to be forwarded to the processes.  The trap is only set after the
+    // initial resize so that it's not picked up.
+    let script = format!(
+        "stty cols 60 rows 20
+        {} multi --no-color -n a:'stty size; sleep 0.2; trap \"stty size; exit\" WINCH; while :; do sleep 0.1; done' </dev/tty &
+        sleep 0.5
+        stty cols 100 rows 30
+        wait",
+        env!("CARGO_BIN_EXE_teetty")
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--no-echo")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().replace('\r', ""),
+        "a | 20 56\na | 30 96\na | [exited with code 0]\n"
+    );
+}
+
+#[test]
+fn test_multi_invalid() {
+    let output = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("multi")
+        .arg("-n")
+        .arg("a:true")
+        .arg("--input")
+        .arg("b")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "teetty: unknown process 'b'\n"
+    );
+}
@@ -1,5 +1,6 @@
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
 
 #[test]
 fn test_stdin() {
@@ -58,3 +59,401 @@ fn test_stdin_script_mode() {
     assert_eq!(out, "BEGIN\n  line1\n  line2\nEND\n");
     assert_eq!(stdout, "BEGIN\n  line1\n  line2\nEND\n");
 }
+
+#[test]
+fn test_stdin_input_log() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let stdin = tempdir.path().join("stdin");
+    let in_log = tempdir.path().join("in_log");
+    let in_log_tagged = tempdir.path().join("in_log_tagged");
+    fs::write(&stdin, "line1\nline2\n").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--in-log")
+        .arg(&in_log)
+        .arg("--no-echo")
+        .arg("--")
+        .arg("tests/read.sh")
+        .stdout(Stdio::null())
+        .stdin(File::open(&stdin).unwrap())
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(0));
+    assert_eq!(fs::read_to_string(&in_log).unwrap(), "line1\nline2\n");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--in-log")
+        .arg(&in_log_tagged)
+        .arg("--in-log-tagged")
+        .arg("--no-echo")
+        .arg("--")
+        .arg("tests/read.sh")
+        .stdout(Stdio::null())
+        .stdin(File::open(&stdin).unwrap())
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(0));
+    let in_log_tagged = fs::read_to_string(&in_log_tagged).unwrap();
+    let (_timestamp, rest) = in_log_tagged.split_once(' ').unwrap();
+    assert_eq!(rest, "stdin line1\\nline2\\n\n");
+}
+
+#[test]
+fn test_triggers() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let secret = tempdir.path().join("secret");
+    let in_log = tempdir.path().join("in_log");
+    fs::write(&secret, "hunter2\n").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--script-mode")
+        .arg("--in-log")
+        .arg(&in_log)
+        .arg("--in-log-tagged")
+        .arg("--on")
+        .arg(r"Continue\? \[y/N\]")
+        .arg("--send")
+        .arg(r"y\n")
+        .arg("--on")
+        .arg("Password:")
+        .arg("--send-file")
+        .arg(&secret)
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(
+            "printf 'Continue? [y/N] '; read a; \
+             printf 'Password: '; read b; \
+             printf 'Continue? [y/N] '; sleep 0.2; \
+             echo \"$a $b\"",
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // stdin is kept open so that the responses are not preceded by an EOF
+    let stdin = child.stdin.take().unwrap();
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    drop(stdin);
+
+    assert!(
+        stdout.ends_with("Continue? [y/N] y hunter2\n"),
+        "{:?}",
+        stdout
+    );
+    let in_log = fs::read_to_string(&in_log).unwrap();
+    let sources = in_log
+        .lines()
+        .map(|x| x.split_once(' ').unwrap().1)
+        .collect::<Vec<_>>();
+    assert_eq!(sources, ["trigger y\\n", "trigger hunter2\\n"]);
+}
+
+#[test]
+fn test_protect_noecho() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let in_log = tempdir.path().join("in_log");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--in-log")
+        .arg(&in_log)
+        .arg("--protect-noecho")
+        .arg("--on")
+        .arg("User:")
+        .arg("--send")
+        .arg(r"bob\n")
+        .arg("--on")
+        .arg("Password:")
+        .arg("--send")
+        .arg(r"hunter2\n")
+        .arg("--on")
+        .arg("Again?")
+        .arg("--send")
+        .arg(r"yes\n")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(
+            "printf 'User: '; read u; \
+             stty -echo; printf 'Password: '; read p; stty echo; \
+             printf 'Again? '; read a; echo \"$u $p $a\"",
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let stdin = child.stdin.take().unwrap();
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    drop(stdin);
+
+    assert!(stdout.ends_with("bob hunter2 yes\r\n"), "{:?}", stdout);
+    assert_eq!(
+        fs::read_to_string(&in_log).unwrap(),
+        "bob\n[input hidden]\nyes\n"
+    );
+}
+
+#[test]
+fn test_stdin_eof_policies() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let fifo = tempdir.path().join("fifo");
+    assert!(Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .unwrap()
+        .success());
+
+    // with wait the process gets end of file once we stop writing and the
+    // followed file was sent.
+    let input = tempdir.path().join("input");
+    fs::write(&input, "line0\n").unwrap();
+    let mut writer = File::options().read(true).write(true).open(&fifo).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--in")
+        .arg(&input)
+        .arg("--in")
+        .arg(&fifo)
+        .arg("--stdin-eof")
+        .arg("wait")
+        .arg("--script-mode")
+        .arg("--")
+        .arg("tests/read.sh")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    writer.write_all(b"line1\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    drop(writer);
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    assert_eq!(stdout, "BEGIN\n  line0\n  line1\nEND\n");
+
+    // with ignore the process keeps running until it gets end of file
+    // through the fifo.
+    let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--in")
+        .arg(&fifo)
+        .arg("--stdin-eof")
+        .arg("ignore")
+        .arg("--script-mode")
+        .arg("--")
+        .arg("tests/read.sh")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    fs::write(&fifo, "line1\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert!(child.try_wait().unwrap().is_none());
+    fs::write(&fifo, "line2\n\x04").unwrap();
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    assert_eq!(stdout, "BEGIN\n  line1\n  line2\nEND\n");
+
+    // with hangup the process is gone right away
+    let status = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--stdin-eof")
+        .arg("hangup")
+        .arg("--")
+        .arg("sleep")
+        .arg("10")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(129));
+}
+
+#[test]
+fn test_follow_file() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let input = tempdir.path().join("input");
+    fs::write(&input, "one\n").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--in")
+        .arg(&input)
+        .arg("--stdin-eof")
+        .arg("ignore")
+        .arg("--script-mode")
+        .arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg("while read -r line; do [ \"$line\" = quit ] && break; echo \"got $line\"; done")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pause = || std::thread::sleep(std::time::Duration::from_millis(300));
+
+    // appended
+    pause();
+    File::options()
+        .append(true)
+        .open(&input)
+        .unwrap()
+        .write_all(b"two\n")
+        .unwrap();
+    // truncated
+    pause();
+    fs::write(&input, "three\n").unwrap();
+    // replaced
+    pause();
+    fs::rename(&input, tempdir.path().join("input.1")).unwrap();
+    fs::write(&input, "four\nquit\n").unwrap();
+
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    assert_eq!(stdout, "got one\ngot two\ngot three\ngot four\n");
+}
+
+#[test]
+fn test_follow_file_stdin_eof_wait() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let input = tempdir.path().join("input");
+    fs::write(&input, "line1\n").unwrap();
+
+    // once stdin ends, the followed file ends when everything was sent
+    let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--in")
+        .arg(&input)
+        .arg("--stdin-eof")
+        .arg("wait")
+        .arg("--script-mode")
+        .arg("--")
+        .arg("tests/read.sh")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    drop(child.stdin.take());
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    assert_eq!(stdout, "BEGIN\n  line1\nEND\n");
+}
+
+#[test]
+fn test_input_backpressure() {
+    let run = |args: &[&str]| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+            .arg("--no-echo")
+            .args(args)
+            .arg("--")
+            .arg("sh")
+            .arg("-c")
+            .arg("head -c 1000000 /dev/zero | tr '\\0' x; echo; echo done")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // the program never reads this, so we must not block on it
+        let mut stdin = child.stdin.take().unwrap();
+        std::thread::spawn(move || {
+            let chunk = [b'y'; 4096];
+            for _ in 0..512 {
+                if stdin.write_all(&chunk).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut stdout)
+            .unwrap();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut stderr)
+            .unwrap();
+        (child.wait().unwrap().code(), stdout, stderr)
+    };
+
+    let (code, stdout, _) = run(&[]);
+    assert_eq!(code, Some(0));
+    assert!(stdout.ends_with("done\r\n"));
+    assert_eq!(stdout.matches('x').count(), 1000000);
+
+    let (code, stdout, _) = run(&["--in-buffer", "1024", "--in-overflow", "drop"]);
+    assert_eq!(code, Some(0));
+    assert!(stdout.ends_with("done\r\n"));
+
+    let (code, _, stderr) = run(&["--in-buffer", "1024", "--in-overflow", "error"]);
+    assert_ne!(code, Some(0));
+    assert!(stderr.contains("No buffer space"), "{}", stderr);
+}
+
+#[test]
+fn test_input_small_buffer() {
+    // even without a buffer, input trickles through
+    let mut child = Command::new(env!("CARGO_BIN_EXE_teetty"))
+        .arg("--no-echo")
+        .arg("--in-buffer")
+        .arg("0")
+        .arg("--")
+        .arg("cat")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"hello\nworld\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "hello\r\nworld\r\n"
+    );
+}
@@ -0,0 +1,187 @@
+//! Async child processes for tokio.
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use nix::errno::Errno;
+use nix::pty::Winsize;
+use nix::sys::wait::waitpid;
+use nix::unistd::{read, write, Pid};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::JoinHandle;
+
+use crate::{exit_code, resize_pty, set_nonblocking, SpawnedPty};
+
+/// A child process running in a pty driven by tokio.
+///
+/// This is returned by [`TtySpawn::spawn_async`](crate::TtySpawn::spawn_async).
+/// The output of the process can be read from [`stdout`](Self::stdout) and
+/// input can be sent to it via [`stdin`](Self::stdin).  In script mode
+/// [`stderr`](Self::stderr) holds the separate stderr stream.
+pub struct AsyncTtyChild {
+    /// Writes into the pty as input.
+    pub stdin: Option<AsyncPtyWriter>,
+    /// Reads the output of the pty.
+    pub stdout: Option<AsyncPtyReader>,
+    /// Reads the output of the stderr pty in script mode.
+    pub stderr: Option<AsyncPtyReader>,
+    master: Arc<AsyncFd<OwnedFd>>,
+    stderr_master: Option<Arc<AsyncFd<OwnedFd>>>,
+    pid: Pid,
+    status: Option<i32>,
+    waiter: Option<JoinHandle<Result<i32, io::Error>>>,
+}
+
+impl AsyncTtyChild {
+    pub(crate) fn new(pty: SpawnedPty) -> Result<AsyncTtyChild, io::Error> {
+        let master = Arc::new(async_fd(pty.master)?);
+        let stderr_master = match pty.stderr_master {
+            Some(fd) => Some(Arc::new(async_fd(fd)?)),
+            None => None,
+        };
+        Ok(AsyncTtyChild {
+            stdin: Some(AsyncPtyWriter {
+                fd: Arc::clone(&master),
+            }),
+            stdout: Some(AsyncPtyReader {
+                fd: Arc::clone(&master),
+            }),
+            stderr: stderr_master
+                .as_ref()
+                .map(|fd| AsyncPtyReader { fd: Arc::clone(fd) }),
+            master,
+            stderr_master,
+            pid: pty.child,
+            status: None,
+            waiter: None,
+        })
+    }
+
+    /// Returns the process ID of the child.
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw() as u32
+    }
+
+    /// Changes the size of the pty and notifies the child.
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<(), io::Error> {
+        Ok(resize_pty(
+            self.master.get_ref().as_fd(),
+            self.stderr_master.as_ref().map(|x| x.get_ref().as_fd()),
+            Winsize {
+                ws_row: rows,
+                ws_col: cols,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            },
+        )?)
+    }
+
+    /// Waits for the child to exit and returns the exit code.
+    ///
+    /// If the child was killed by a signal the exit code is 128 plus the
+    /// signal number as with shells.
+    ///
+    /// This is cancel safe: if the future is dropped the child is still
+    /// reaped in the background and a later call returns its exit code.
+    pub async fn wait(&mut self) -> Result<i32, io::Error> {
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+        let pid = self.pid;
+        let waiter = self.waiter.get_or_insert_with(|| {
+            tokio::task::spawn_blocking(move || loop {
+                match waitpid(pid, None) {
+                    Ok(status) => {
+                        if let Some(code) = exit_code(status) {
+                            return Ok(code);
+                        }
+                    }
+                    Err(Errno::EINTR) => {}
+                    Err(err) => return Err(io::Error::from(err)),
+                }
+            })
+        });
+        // awaiting the handle by reference leaves it in place if this future
+        // is dropped, so the next call picks up the same task.
+        let rv = waiter.await;
+        self.waiter = None;
+        let status = rv.map_err(|err| io::Error::new(io::ErrorKind::Other, err))??;
+        self.status = Some(status);
+        Ok(status)
+    }
+}
+
+/// Reads output from a pty.
+pub struct AsyncPtyReader {
+    fd: Arc<AsyncFd<OwnedFd>>,
+}
+
+impl AsyncRead for AsyncPtyReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        loop {
+            let mut guard = match self.fd.poll_read_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            let unfilled = buf.initialize_unfilled();
+            let rv = guard.try_io(|fd| Ok(read(fd.get_ref().as_raw_fd(), unfilled)?));
+            match rv {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                // on linux a closed tty raises EIO
+                Ok(Err(err)) if err.raw_os_error() == Some(Errno::EIO as i32) => {
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Writes input into a pty.
+pub struct AsyncPtyWriter {
+    fd: Arc<AsyncFd<OwnedFd>>,
+}
+
+impl AsyncWrite for AsyncPtyWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        loop {
+            let mut guard = match self.fd.poll_write_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            match guard.try_io(|fd| Ok(write(fd.get_ref(), buf)?)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Switches the fd to non blocking mode and registers it with tokio.
+fn async_fd(fd: OwnedFd) -> Result<AsyncFd<OwnedFd>, io::Error> {
+    set_nonblocking(fd.as_fd())?;
+    AsyncFd::new(fd)
+}
@@ -0,0 +1,171 @@
+//! Children with programmatic access to the pty.
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::sync::Arc;
+
+use nix::errno::Errno;
+use nix::pty::Winsize;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{read, write, Pid};
+
+use crate::{exit_code, hangup, kill_all, resize_pty, SpawnedPty};
+
+/// A child process running in a pty that is not connected to our terminal.
+///
+/// This is returned by [`TtySpawn::spawn_child`](crate::TtySpawn::spawn_child).
+/// The output of the process can be read from [`stdout`](Self::stdout) and
+/// input can be sent to it via [`stdin`](Self::stdin).  In script mode
+/// [`stderr`](Self::stderr) holds the separate stderr stream.
+pub struct TtyChild {
+    /// Writes into the pty as input.
+    pub stdin: Option<PtyWriter>,
+    /// Reads the output of the pty.
+    pub stdout: Option<PtyReader>,
+    /// Reads the output of the stderr pty in script mode.
+    pub stderr: Option<PtyReader>,
+    master: Arc<OwnedFd>,
+    stderr_master: Option<Arc<OwnedFd>>,
+    pid: Pid,
+    status: Option<i32>,
+}
+
+impl TtyChild {
+    pub(crate) fn new(pty: SpawnedPty) -> TtyChild {
+        let master = Arc::new(pty.master);
+        let stderr_master = pty.stderr_master.map(Arc::new);
+        TtyChild {
+            stdin: Some(PtyWriter {
+                fd: Arc::clone(&master),
+            }),
+            stdout: Some(PtyReader {
+                fd: Arc::clone(&master),
+            }),
+            stderr: stderr_master
+                .as_ref()
+                .map(|fd| PtyReader { fd: Arc::clone(fd) }),
+            master,
+            stderr_master,
+            pid: pty.child,
+            status: None,
+        }
+    }
+
+    /// Returns the process ID of the child.
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw() as u32
+    }
+
+    /// Changes the size of the pty and notifies the child.
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<(), io::Error> {
+        Ok(resize_pty(
+            self.master.as_fd(),
+            self.stderr_master.as_ref().map(|x| x.as_fd()),
+            Winsize {
+                ws_row: rows,
+                ws_col: cols,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            },
+        )?)
+    }
+
+    /// Hangs up the child.
+    ///
+    /// This sends `SIGHUP` to the child and the foreground process group of
+    /// the pty, like a terminal that is closed.
+    pub fn hangup(&self) {
+        if self.status.is_none() {
+            hangup(self.master.as_fd(), self.pid);
+        }
+    }
+
+    /// Kills the child and the foreground process group of the pty with
+    /// `SIGKILL`.
+    pub fn kill(&self) {
+        if self.status.is_none() {
+            kill_all(self.master.as_fd(), self.pid);
+        }
+    }
+
+    /// Waits for the child to exit and returns the exit code.
+    ///
+    /// If the child was killed by a signal the exit code is 128 plus the
+    /// signal number as with shells.
+    pub fn wait(&mut self) -> Result<i32, io::Error> {
+        while self.status.is_none() {
+            self.reap(None)?;
+        }
+        Ok(self.status.unwrap())
+    }
+
+    /// Returns the exit code if the child exited, without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<i32>, io::Error> {
+        if self.status.is_none() {
+            self.reap(Some(WaitPidFlag::WNOHANG))?;
+        }
+        Ok(self.status)
+    }
+
+    fn reap(&mut self, flags: Option<WaitPidFlag>) -> Result<(), io::Error> {
+        match waitpid(self.pid, flags) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::EINTR) => {}
+            Ok(status) => self.status = exit_code(status),
+            Err(err) => return Err(err.into()),
+        }
+        Ok(())
+    }
+}
+
+/// Reads output from a pty.
+///
+/// Once the child closed the pty, reads return end of file.
+pub struct PtyReader {
+    fd: Arc<OwnedFd>,
+}
+
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match read(self.fd.as_raw_fd(), buf) {
+                Ok(n) => return Ok(n),
+                // on linux a closed tty raises EIO
+                Err(Errno::EIO) => return Ok(0),
+                Err(Errno::EINTR) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+impl AsFd for PtyReader {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Writes input into a pty.
+pub struct PtyWriter {
+    fd: Arc<OwnedFd>,
+}
+
+impl Write for PtyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match write(&*self.fd, buf) {
+                Ok(n) => return Ok(n),
+                Err(Errno::EINTR) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsFd for PtyWriter {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
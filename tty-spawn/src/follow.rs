@@ -0,0 +1,191 @@
+//! Following regular input files.
+//!
+//! Regular files are always reported as readable, so instead of polling the
+//! file itself a helper thread watches it and wakes up the communication loop
+//! through a socket pair.  On Linux the directory of the file is watched with
+//! inotify, elsewhere the thread wakes up periodically.  Like `tail -F` the file
+//! is read from where we left off, and it's read from the start again when it
+//! was truncated or replaced (such as by log rotation).
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+#[cfg(not(target_os = "linux"))]
+use std::time::Duration;
+
+use crate::source::InputSource;
+
+/// How often the file is checked on platforms without inotify.
+#[cfg(not(target_os = "linux"))]
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Follows a regular file like `tail -F`.
+pub(crate) struct FollowSource {
+    path: PathBuf,
+    name: String,
+    file: File,
+    // device and inode of the file that is read to detect when it's replaced
+    id: (u64, u64),
+    pos: u64,
+    // once closed, the source ends when it caught up with the file
+    closed: bool,
+    wake_reader: UnixStream,
+    wake_writer: UnixStream,
+}
+
+impl FollowSource {
+    pub fn open(path: &Path) -> Result<FollowSource, io::Error> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        let (wake_reader, wake_writer) = UnixStream::pair()?;
+        wake_reader.set_nonblocking(true)?;
+        wake_writer.set_nonblocking(true)?;
+        spawn_watcher(path, wake_writer.try_clone()?)?;
+        let source = FollowSource {
+            path: path.to_path_buf(),
+            name: path.display().to_string(),
+            file,
+            id: (meta.dev(), meta.ino()),
+            pos: 0,
+            closed: false,
+            wake_reader,
+            wake_writer,
+        };
+        // what's already in the file is read right away
+        source.wake();
+        Ok(source)
+    }
+
+    fn wake(&self) {
+        // if this fails, there is a wakeup pending already
+        (&self.wake_writer).write_all(&[0]).ok();
+    }
+}
+
+impl AsFd for FollowSource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.wake_reader.as_fd()
+    }
+}
+
+impl InputSource for FollowSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        // the wakeups are consumed first so that changes from now on wake us
+        // up again.
+        let mut scratch = [0; 64];
+        while matches!((&self.wake_reader).read(&mut scratch), Ok(n) if n > 0) {}
+
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 {
+                self.pos += n as u64;
+                // there might be more, make sure we come back
+                self.wake();
+                return Ok(n);
+            }
+
+            // we caught up with the file, check if it was replaced or
+            // truncated in the meantime.
+            match fs::metadata(&self.path) {
+                Ok(meta) if (meta.dev(), meta.ino()) != self.id => {
+                    self.file = File::open(&self.path)?;
+                    self.id = (meta.dev(), meta.ino());
+                    self.pos = 0;
+                }
+                Ok(meta) if meta.len() < self.pos => {
+                    self.file.seek(SeekFrom::Start(0))?;
+                    self.pos = 0;
+                }
+                _ if self.closed => return Ok(0),
+                _ => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        // nothing might change anymore, so read once more to get to the end
+        self.wake();
+    }
+}
+
+/// Starts the thread that wakes up the source when the file might have changed.
+///
+/// The thread ends once the source is gone.  As nothing is ever sent back
+/// through the socket pair, it becomes readable only when the source closed
+/// its end.
+#[cfg(target_os = "linux")]
+fn spawn_watcher(path: &Path, mut wake: UnixStream) -> Result<(), io::Error> {
+    use nix::errno::Errno;
+    use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+
+    let file_name = path.file_name().map(|x| x.to_os_string());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    // the directory is watched so that a replaced file is picked up too
+    inotify.add_watch(
+        dir,
+        AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_ATTRIB
+            | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_CLOSE_WRITE,
+    )?;
+    thread::Builder::new()
+        .name("tty-spawn-follow".into())
+        .spawn(move || loop {
+            let mut fds = [
+                PollFd::new(inotify.as_fd(), PollFlags::POLLIN),
+                PollFd::new(wake.as_fd(), PollFlags::POLLIN),
+            ];
+            match poll(&mut fds, PollTimeout::NONE) {
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(_) => return,
+            }
+            if fds[1].any().unwrap_or(true) {
+                return;
+            }
+            let events = match inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EINTR) => continue,
+                Err(_) => return,
+            };
+            if events.iter().any(|x| x.name == file_name) && !wake_up(&mut wake) {
+                return;
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn spawn_watcher(_path: &Path, mut wake: UnixStream) -> Result<(), io::Error> {
+    thread::Builder::new()
+        .name("tty-spawn-follow".into())
+        .spawn(move || loop {
+            thread::sleep(FOLLOW_INTERVAL);
+            if !wake_up(&mut wake) {
+                return;
+            }
+        })?;
+    Ok(())
+}
+
+/// Wakes up the source, returns `false` if it's gone.
+fn wake_up(wake: &mut UnixStream) -> bool {
+    match wake.write_all(&[0]) {
+        Ok(()) => true,
+        Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+    }
+}
@@ -2,29 +2,98 @@
 //! [`teetty`](https://github.com/mitsuhiko/teetty) is built.  It lets you spawn
 //! processes in a fake TTY and duplex stdin/stdout so you can communicate with an
 //! otherwise user attended process.
+use std::convert::Infallible;
 use std::ffi::{CString, OsStr, OsString};
-use std::fs::File;
-use std::io::Write;
-use std::os::fd::{AsFd, BorrowedFd, IntoRawFd, OwnedFd};
-use std::os::unix::prelude::{AsRawFd, OpenOptionsExt, OsStrExt};
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read};
+use std::net::ToSocketAddrs;
+use std::os::fd::{AsFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::prelude::{AsRawFd, FileTypeExt, OpenOptionsExt, OsStrExt};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{env, io};
+use std::time::{Duration, Instant};
+use std::{env, io, mem};
 
 use nix::errno::Errno;
-use nix::libc::{login_tty, O_NONBLOCK, TIOCGWINSZ, TIOCSWINSZ, VEOF};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::libc::{self, c_int, login_tty, O_NONBLOCK, TIOCGWINSZ, TIOCSWINSZ, VEOF};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use nix::pty::{openpty, Winsize};
-use nix::sys::select::{select, FdSet};
-use nix::sys::signal::{killpg, Signal};
-use nix::sys::stat::Mode;
-use nix::sys::termios::{
-    cfmakeraw, tcgetattr, tcsetattr, LocalFlags, OutputFlags, SetArg, Termios,
+use nix::sys::signal::{kill, killpg, raise, Signal};
+use nix::sys::stat::{fstat, Mode, SFlag};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, OutputFlags, SetArg};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{
+    dup2, execvp, fork, getpgrp, isatty, mkfifo, read, tcgetpgrp, write, ForkResult, Pid,
 };
-use nix::sys::time::TimeVal;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{dup2, execvp, fork, isatty, mkfifo, read, tcgetpgrp, write, ForkResult, Pid};
-use signal_hook::consts::SIGWINCH;
+use signal_hook::consts::{SIGCHLD, SIGCONT, SIGTSTP, SIGWINCH};
+
+use crate::follow::FollowSource;
+use crate::logger::{InputLog, LogConfig, Logger, Output, SessionLog};
+use crate::osc::OscParser;
+use crate::passthrough::{splice_output, ReadBuf};
+use crate::process::ProcessSettings;
+use crate::session::{log_footer, log_header, RunInfo, Session};
+use crate::signals::SignalHooks;
+use crate::sink::FileSink;
+use crate::source::{FileSource, InputQueue};
+use crate::stream::{StreamAddr, StreamOutput};
+use crate::term::{ModeTracker, RestoreTerm};
+use crate::trigger::Triggers;
+
+pub use crate::child::{PtyReader, PtyWriter, TtyChild};
+pub use crate::logger::FsyncPolicy;
+pub use crate::process::ResourceLimit;
+pub use crate::session::LogHeaderFormat;
+pub use crate::sink::OutputSink;
+pub use crate::source::{InputOverflowPolicy, InputSource, StdinEofPolicy};
+pub use crate::stream::{OverflowPolicy, DEFAULT_BUFFER_SIZE};
+pub use crate::term::reset_terminal;
+pub use crate::trigger::Trigger;
+
+#[cfg(feature = "tokio")]
+pub use crate::async_pty::{AsyncPtyReader, AsyncPtyWriter, AsyncTtyChild};
+
+#[cfg(feature = "tokio")]
+mod async_pty;
+mod child;
+mod follow;
+mod logger;
+mod osc;
+mod passthrough;
+mod process;
+mod redact;
+mod session;
+mod signals;
+mod sink;
+mod source;
+mod stream;
+mod term;
+mod trigger;
+
+/// How long output is drained at most after the child exited.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The drain ends early once no output arrived for this long.
+const DRAIN_IDLE_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Controls what happens when the child exits while other processes still
+/// hold the pty open.
+///
+/// This is the case when the child leaves background processes behind.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ChildExitPolicy {
+    /// Keeps going until all processes closed the pty.
+    #[default]
+    Wait,
+    /// Drains the remaining output for a short while and exits.
+    Exit,
+    /// Kills the process group of the child and the foreground process group
+    /// of the pty, then exits like [`Exit`](Self::Exit).
+    Kill,
+}
 
 /// Lets you spawn processes with a TTY connected.
 pub struct TtySpawn {
@@ -37,8 +106,30 @@ impl TtySpawn {
         TtySpawn {
             options: Some(SpawnOptions {
                 command: vec![cmd.as_ref().to_os_string()],
-                stdin_file: None,
-                stdout_file: None,
+                sources: Vec::new(),
+                stdin_eof: StdinEofPolicy::default(),
+                child_exit: ChildExitPolicy::default(),
+                outputs: Vec::new(),
+                stream_outputs: Vec::new(),
+                triggers: Vec::new(),
+                redact: Vec::new(),
+                protect_noecho: false,
+                events_file: None,
+                status_path: None,
+                strip_osc: false,
+                log_header: None,
+                pre_exec: Vec::new(),
+                close_fds: true,
+                pass_fds: Vec::new(),
+                process: ProcessSettings::default(),
+                input_log_file: None,
+                input_log_tagged: false,
+                output_buffer_size: DEFAULT_BUFFER_SIZE,
+                output_overflow: OverflowPolicy::default(),
+                input_buffer_size: DEFAULT_BUFFER_SIZE,
+                input_overflow: InputOverflowPolicy::default(),
+                flush_interval: None,
+                fsync: FsyncPolicy::default(),
                 script_mode: false,
                 no_flush: false,
                 no_echo: false,
@@ -76,10 +167,12 @@ impl TtySpawn {
         self
     }
 
-    /// Sets an input file for stdin.
+    /// Adds an input file for stdin.
     ///
-    /// It's recommended that this is a named pipe and as a general recommendation
-    /// this file should be opened with `O_NONBLOCK`.
+    /// This can be called multiple times in which case all input files are
+    /// monitored independently.  It's recommended that this is a named pipe
+    /// and as a general recommendation this file should be opened with
+    /// `O_NONBLOCK`.
     ///
     /// # Platform Specifics
     ///
@@ -91,49 +184,402 @@ impl TtySpawn {
     /// solution to this problem is to ensure that there is at least always one
     /// writer open which can be ensured by also opening this file for writing.
     pub fn stdin_file(&mut self, f: File) -> &mut Self {
-        self.options_mut().stdin_file = Some(f);
-        self
+        self.add_source(FileSource {
+            file: f,
+            name: "in".into(),
+            keepalive: None,
+        })
     }
 
-    /// Sets a path as input file for stdin.
+    /// Adds a path as input file for stdin.
+    ///
+    /// If the path does not exist, a FIFO is created.  If it's a regular file,
+    /// it's followed like with `tail -F`: first everything in it is sent, then
+    /// what's appended to it.  When the file is truncated or replaced, it's
+    /// read from the start again.
     pub fn stdin_path<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, io::Error> {
         let path = path.as_ref();
+        if fs::metadata(path).map_or(false, |x| x.is_file()) {
+            return Ok(self.add_source(FollowSource::open(path)?));
+        }
         mkfifo_atomic(path)?;
-        // for the justification for write(true) see the explanation on
-        // [`stdin_file`](Self::stdin_file).
-        Ok(self.stdin_file(
-            File::options()
-                .read(true)
-                .write(true)
-                .custom_flags(O_NONBLOCK)
-                .open(path)?,
-        ))
+        // for the justification for the writer see the explanation on
+        // [`stdin_file`](Self::stdin_file).  It's kept separately so that the
+        // FIFO can come to an end with `StdinEofPolicy::Wait`.
+        let file = File::options()
+            .read(true)
+            .custom_flags(O_NONBLOCK)
+            .open(path)?;
+        let keepalive = File::options()
+            .write(true)
+            .custom_flags(O_NONBLOCK)
+            .open(path)?;
+        Ok(self.add_source(FileSource {
+            file,
+            name: path.display().to_string(),
+            keepalive: Some(keepalive),
+        }))
     }
 
-    /// Sets an output file for stdout.
-    pub fn stdout_file(&mut self, f: File) -> &mut Self {
-        self.options_mut().stdout_file = Some(f);
+    /// Sets what happens when stdin reaches end of file.
+    ///
+    /// By default end of file is sent to the process, which ends most
+    /// interactive programs.  When stdin is not needed (such as with
+    /// `</dev/null`), other policies keep the process running so that it can be
+    /// driven through input files.
+    pub fn stdin_eof(&mut self, policy: StdinEofPolicy) -> &mut Self {
+        self.options_mut().stdin_eof = policy;
+        self
+    }
+
+    /// Sets what happens when the child exits while the pty is still open.
+    ///
+    /// By default this keeps going until all processes closed the pty, which
+    /// means background processes the child left behind keep this running.
+    pub fn child_exit(&mut self, policy: ChildExitPolicy) -> &mut Self {
+        self.options_mut().child_exit = policy;
         self
     }
 
-    /// Sets a path as output file for stdout.
+    /// Adds a custom source of input.
+    ///
+    /// Like input files, the source is monitored and everything read from it
+    /// is sent to the terminal as input.  See [`InputSource`] for details.
+    pub fn add_source<S: InputSource + 'static>(&mut self, source: S) -> &mut Self {
+        self.options_mut().sources.push(Box::new(source));
+        self
+    }
+
+    /// Adds an output file for stdout.
+    ///
+    /// This can be called multiple times in which case the output is written
+    /// to all files in the order they were added.
+    pub fn stdout_file(&mut self, f: File) -> &mut Self {
+        self.add_output(Box::new(FileSink(BufWriter::new(f))), None)
+    }
+
+    /// Adds an output file for stdout with its own flush setting.
+    ///
+    /// This works like [`stdout_file`](Self::stdout_file) but the file is
+    /// flushed (or not) independently of what was set with [`flush`](Self::flush).
+    pub fn stdout_file_with_flush(&mut self, f: File, flush: bool) -> &mut Self {
+        self.add_output(Box::new(FileSink(BufWriter::new(f))), Some(flush))
+    }
+
+    /// Adds a path as output file for stdout.
     ///
     /// If the `truncate` flag is set to `true` the file will be truncated
     /// first, otherwise it will be appended to.
+    ///
+    /// If the path points to a FIFO, it's written to without blocking like a
+    /// socket (see [`stdout_unix_socket`](Self::stdout_unix_socket)).  Until a
+    /// reader opens the FIFO the output is buffered.
     pub fn stdout_path<P: AsRef<Path>>(
         &mut self,
         path: P,
         truncate: bool,
     ) -> Result<&mut Self, io::Error> {
-        Ok(self.stdout_file(if !truncate {
-            File::options().append(true).create(true).open(path)?
+        let path = path.as_ref();
+        if fs::metadata(path).map_or(false, |x| x.file_type().is_fifo()) {
+            let addr = StreamAddr::Fifo(path.to_path_buf());
+            Ok(self.add_stream_output(StreamOutput::new(addr)))
         } else {
-            File::options()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(path)?
-        }))
+            Ok(self.stdout_file(open_log_file(path, truncate)?))
+        }
+    }
+
+    /// Adds a unix socket as output for stdout.
+    ///
+    /// The output is streamed into the socket the same way as it's written to
+    /// output files.  Writes never block, instead output is held in a bounded
+    /// buffer until the receiver is ready for it (see
+    /// [`output_buffer`](Self::output_buffer)).  If nothing is listening on
+    /// the socket, or the receiver goes away, the connection is retried with an
+    /// increasing backoff.
+    pub fn stdout_unix_socket<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        let addr = StreamAddr::Unix(path.as_ref().to_path_buf());
+        self.add_stream_output(StreamOutput::new(addr))
+    }
+
+    /// Adds a TCP socket as output for stdout.
+    ///
+    /// The address is resolved immediately.  Otherwise this works like
+    /// [`stdout_unix_socket`](Self::stdout_unix_socket).
+    pub fn stdout_tcp_socket<A: ToSocketAddrs>(&mut self, addr: A) -> Result<&mut Self, io::Error> {
+        let addr = StreamAddr::Tcp(addr.to_socket_addrs()?.collect());
+        Ok(self.add_stream_output(StreamOutput::new(addr)))
+    }
+
+    /// Adds a custom sink for stdout.
+    ///
+    /// The sink receives the output just like output files.  See
+    /// [`OutputSink`] for details.
+    pub fn add_sink<S: OutputSink + 'static>(&mut self, sink: S) -> &mut Self {
+        self.add_output(Box::new(sink), None)
+    }
+
+    /// Configures the buffer of FIFO and socket outputs.
+    ///
+    /// Each FIFO and socket output holds up to `size` bytes (defaults to
+    /// [`DEFAULT_BUFFER_SIZE`]) of output that could not be written yet.  The
+    /// `overflow` policy controls what happens when the buffer is full.  Unless
+    /// [`OverflowPolicy::Block`] is used, a slow or missing receiver never
+    /// holds up the terminal.
+    pub fn output_buffer(&mut self, size: usize, overflow: OverflowPolicy) -> &mut Self {
+        let opts = self.options_mut();
+        opts.output_buffer_size = size;
+        opts.output_overflow = overflow;
+        self
+    }
+
+    /// Configures the buffer of input waiting for the process.
+    ///
+    /// Input is only written while the pty accepts it, so a process that is
+    /// not reading its input never keeps its output from being forwarded.  Up
+    /// to `size` bytes (defaults to [`DEFAULT_BUFFER_SIZE`], at least one) are
+    /// held per source, the `overflow` policy controls what happens beyond
+    /// that.  Note that [`InputOverflowPolicy::Error`] makes the spawn fail.
+    pub fn input_buffer(&mut self, size: usize, overflow: InputOverflowPolicy) -> &mut Self {
+        let opts = self.options_mut();
+        opts.input_buffer_size = size;
+        opts.input_overflow = overflow;
+        self
+    }
+
+    /// Adds a trigger that responds to output.
+    ///
+    /// When the output of the process matches the pattern of the trigger, the
+    /// response is sent to the terminal as input.  See [`Trigger`] for details.
+    pub fn add_trigger(&mut self, trigger: Trigger) -> &mut Self {
+        self.options_mut().triggers.push(trigger);
+        self
+    }
+
+    /// Redacts matches of a regular expression in the logs.
+    ///
+    /// Matches are replaced with `[REDACTED]` in the output files and sinks as
+    /// well as in the input log.  The output to the terminal is not changed.
+    /// To catch matches that are split across chunks, the last 1KB of output is
+    /// held back until more output arrives or the output goes idle for 100ms.
+    /// The same applies to input, so chunks of input that were held back
+    /// together end up on a single line in the tagged input log.
+    pub fn redact(&mut self, pattern: &str) -> Result<&mut Self, io::Error> {
+        regex::bytes::Regex::new(pattern)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.options_mut().redact.push(pattern.to_string());
+        Ok(self)
+    }
+
+    /// Redacts a literal string in the logs.
+    ///
+    /// This works like [`redact`](Self::redact) but matches the text as is.
+    pub fn redact_literal(&mut self, text: &str) -> &mut Self {
+        if !text.is_empty() {
+            self.options_mut().redact.push(regex::escape(text));
+        }
+        self
+    }
+
+    /// Sets a file that records all input.
+    ///
+    /// Everything read from stdin as well as from the input files added with
+    /// [`stdin_file`](Self::stdin_file) is written into this file before it
+    /// is sent to the terminal.
+    pub fn input_log_file(&mut self, f: File) -> &mut Self {
+        self.options_mut().input_log_file = Some(f);
+        self
+    }
+
+    /// Sets a path as file that records all input.
+    ///
+    /// If the `truncate` flag is set to `true` the file will be truncated
+    /// first, otherwise it will be appended to.
+    pub fn input_log_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        truncate: bool,
+    ) -> Result<&mut Self, io::Error> {
+        Ok(self.input_log_file(open_log_file(path.as_ref(), truncate)?))
+    }
+
+    /// Tags the chunks written to the input log.
+    ///
+    /// By default the input is logged verbatim.  When tagging is enabled every
+    /// chunk is instead written as a separate line which carries a timestamp
+    /// (seconds since the unix epoch), the source of the input (`stdin`, the path
+    /// of the input file, `in` if the file was added without a path, `trigger`
+    /// for responses of a [`Trigger`] or the name of a custom [`InputSource`]) and
+    /// the input itself with non printable characters escaped.
+    pub fn input_log_tagged(&mut self, yes: bool) -> &mut Self {
+        self.options_mut().input_log_tagged = yes;
+        self
+    }
+
+    /// Keeps input out of the input log while the terminal has echo disabled.
+    ///
+    /// Programs asking for passwords typically turn off echo while staying in
+    /// canonical (line) mode.  When this is enabled, input sent during that
+    /// time is not recorded in the input log, instead a single `[input hidden]`
+    /// marker is written on a line of its own.  Note that if echo is disabled altogether (see
+    /// [`echo`](Self::echo) and [`script_mode`](Self::script_mode)), this
+    /// hides all input that is sent while the terminal is in canonical mode.
+    pub fn protect_noecho(&mut self, yes: bool) -> &mut Self {
+        self.options_mut().protect_noecho = yes;
+        self
+    }
+
+    /// Sets a file that receives session events.
+    ///
+    /// Events are written as JSON lines with a `ts` (seconds since the unix
+    /// epoch) and `event` key.  Currently the following events are emitted:
+    ///
+    /// * `foreground`: the foreground process of the terminal changed (for
+    ///   instance from a shell to `vim`).  The `process` key holds its `pid`
+    ///   and on Linux its `name` and `cmdline`.
+    /// * `title`: a program set the window `title` with an OSC 0 or 2 sequence.
+    /// * `cwd`: a program reported its working directory (`cwd`) with an OSC 7
+    ///   sequence.
+    /// * `exit`: the process exited with `exit_code`.
+    pub fn events_file(&mut self, f: File) -> &mut Self {
+        self.options_mut().events_file = Some(f);
+        self
+    }
+
+    /// Sets a path as file for session events.
+    ///
+    /// If the `truncate` flag is set to `true` the file will be truncated
+    /// first, otherwise it will be appended to.
+    pub fn events_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        truncate: bool,
+    ) -> Result<&mut Self, io::Error> {
+        Ok(self.events_file(open_log_file(path.as_ref(), truncate)?))
+    }
+
+    /// Sets a path where the current state of the session is kept.
+    ///
+    /// Whenever the state changes, the file is atomically replaced with a JSON
+    /// object holding the `pid` of the process, the current `foreground`
+    /// process, the `title` and `cwd` reported by programs and once the
+    /// process exited its `exit_code`.
+    pub fn status_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.options_mut().status_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Strips title and working directory sequences from the output files.
+    ///
+    /// These are the OSC 0, 1, 2 and 7 sequences which programs use to set the
+    /// window title and report the working directory.  The terminal output is
+    /// not changed.
+    pub fn strip_osc(&mut self, yes: bool) -> &mut Self {
+        self.options_mut().strip_osc = yes;
+        self
+    }
+
+    /// Writes a header and footer around the run into the output files.
+    ///
+    /// The header records the command line, start time, pid, working
+    /// directory, terminal size and version, the footer the exit status and
+    /// duration.  This makes it possible to split a log that several runs
+    /// appended to back into the individual runs.
+    pub fn log_header(&mut self, format: LogHeaderFormat) -> &mut Self {
+        self.options_mut().log_header = Some(format);
+        self
+    }
+
+    /// Schedules a closure to run in the child right before the command is
+    /// executed.
+    ///
+    /// The closure runs after the pty was connected to the standard streams,
+    /// the file descriptors were set up and the resource limits and
+    /// credentials were applied.  If it fails, spawning fails
+    /// with its error.  This can be called multiple times in which case the
+    /// closures run in order.
+    ///
+    /// # Safety
+    ///
+    /// The closure runs in the forked child where only async-signal-safe
+    /// operations are allowed.  The same restrictions as for
+    /// [`CommandExt::pre_exec`](std::os::unix::process::CommandExt::pre_exec)
+    /// apply.
+    pub unsafe fn pre_exec<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnMut() -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.options_mut().pre_exec.push(Box::new(f));
+        self
+    }
+
+    /// Controls if file descriptors are closed in the child.
+    ///
+    /// By default all file descriptors other than the standard streams and
+    /// the ones passed with [`pass_fd`](Self::pass_fd) are closed when the
+    /// command is executed, so that the child does not hold on to our log
+    /// files and FIFOs.  Setting this to `false` lets the child inherit all
+    /// file descriptors that are not marked close-on-exec.
+    pub fn close_fds(&mut self, yes: bool) -> &mut Self {
+        self.options_mut().close_fds = yes;
+        self
+    }
+
+    /// Passes a file descriptor to the child at a fixed number.
+    ///
+    /// The file descriptor is duplicated, so the given one can be closed
+    /// afterwards.  In the child it's available as `target` (which can also
+    /// replace one of the standard streams).
+    pub fn pass_fd<F: AsFd>(&mut self, fd: F, target: RawFd) -> Result<&mut Self, io::Error> {
+        let fd = fd.as_fd().try_clone_to_owned()?;
+        self.options_mut().pass_fds.push((fd, target));
+        Ok(self)
+    }
+
+    /// Sets a resource limit for the child.
+    ///
+    /// `None` means unlimited.  The hard limit can only be raised with the
+    /// necessary privileges.
+    pub fn rlimit(
+        &mut self,
+        limit: ResourceLimit,
+        soft: Option<u64>,
+        hard: Option<u64>,
+    ) -> &mut Self {
+        self.options_mut().process.rlimits.push((limit, soft, hard));
+        self
+    }
+
+    /// Sets the umask of the child.
+    pub fn umask(&mut self, mask: u32) -> &mut Self {
+        self.options_mut().process.umask = Some(mask);
+        self
+    }
+
+    /// Sets the nice value of the child.
+    pub fn nice(&mut self, nice: i32) -> &mut Self {
+        self.options_mut().process.nice = Some(nice);
+        self
+    }
+
+    /// Sets the user ID the child runs as.
+    ///
+    /// Unless [`groups`](Self::groups) is used, the supplementary groups are
+    /// cleared when switching users as root.
+    pub fn uid(&mut self, uid: u32) -> &mut Self {
+        self.options_mut().process.uid = Some(uid);
+        self
+    }
+
+    /// Sets the group ID the child runs as.
+    pub fn gid(&mut self, gid: u32) -> &mut Self {
+        self.options_mut().process.gid = Some(gid);
+        self
+    }
+
+    /// Sets the supplementary groups of the child.
+    pub fn groups(&mut self, groups: &[u32]) -> &mut Self {
+        self.options_mut().process.groups = Some(groups.to_vec());
+        self
     }
 
     /// Enables script mode.
@@ -149,12 +595,34 @@ impl TtySpawn {
 
     /// Can be used to turn flushing off.
     ///
-    /// By default output is flushed constantly.
+    /// By default output is flushed constantly.  This applies to all output
+    /// files which were not added with an explicit flush setting and the
+    /// input log.  Note that log files are written on a separate thread, so
+    /// even with flushing enabled, writing them never holds up the terminal
+    /// unless the log storage falls far behind.
     pub fn flush(&mut self, yes: bool) -> &mut Self {
         self.options_mut().no_flush = !yes;
         self
     }
 
+    /// Sets an interval in which unflushed log files are flushed.
+    ///
+    /// When flushing is turned off, output is otherwise only written once
+    /// enough of it accumulated.  This makes sure it's written out at least
+    /// in the given interval.
+    pub fn flush_interval(&mut self, interval: Duration) -> &mut Self {
+        self.options_mut().flush_interval = Some(interval);
+        self
+    }
+
+    /// Sets the policy for syncing log files to disk.
+    ///
+    /// By default log files are never synced explicitly.
+    pub fn fsync(&mut self, policy: FsyncPolicy) -> &mut Self {
+        self.options_mut().fsync = policy;
+        self
+    }
+
     /// Can be used to turn echo off.
     ///
     /// By default echo is turned on.
@@ -190,15 +658,79 @@ impl TtySpawn {
         )?)
     }
 
+    /// Spawns the application in a TTY without connecting it to our terminal.
+    ///
+    /// Unlike [`spawn`](Self::spawn) this does not touch the standard streams
+    /// of the current process and it does not block.  Instead the returned
+    /// [`TtyChild`] gives access to the output and input of the pty, which is
+    /// useful to render the terminal of a child somewhere else.  Input files,
+    /// output files and the input log are not used in this mode.  The pty
+    /// starts out with a size of 80x24.
+    pub fn spawn_child(&mut self) -> Result<TtyChild, io::Error> {
+        let mut opts = self.options.take().expect("builder only works once");
+        Ok(TtyChild::new(spawn_pty(&mut opts, false)?))
+    }
+
+    /// Spawns the application in a TTY driven by tokio.
+    ///
+    /// Unlike [`spawn`](Self::spawn) this does not connect the process to
+    /// the terminal of the current process and it does not block.  Instead
+    /// the returned [`AsyncTtyChild`] gives access to the output and input of
+    /// the pty.  Input files, output files and the input log are not used in
+    /// this mode.  The pty starts out with a size of 80x24.
+    ///
+    /// This requires the `tokio` feature and must be called from within a
+    /// tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_async(&mut self) -> Result<AsyncTtyChild, io::Error> {
+        let mut opts = self.options.take().expect("builder only works once");
+        AsyncTtyChild::new(spawn_pty(&mut opts, false)?)
+    }
+
+    fn add_output(&mut self, sink: Box<dyn OutputSink>, flush: Option<bool>) -> &mut Self {
+        self.options_mut().outputs.push(Output { sink, flush });
+        self
+    }
+
+    fn add_stream_output(&mut self, stream: StreamOutput) -> &mut Self {
+        self.options_mut().stream_outputs.push(stream);
+        self
+    }
+
     fn options_mut(&mut self) -> &mut SpawnOptions {
         self.options.as_mut().expect("builder only works once")
     }
 }
 
+/// A closure that runs in the child before the command is executed.
+type PreExecHook = Box<dyn FnMut() -> io::Result<()> + Send + Sync>;
+
 struct SpawnOptions {
     command: Vec<OsString>,
-    stdin_file: Option<File>,
-    stdout_file: Option<File>,
+    sources: Vec<Box<dyn InputSource>>,
+    stdin_eof: StdinEofPolicy,
+    child_exit: ChildExitPolicy,
+    outputs: Vec<Output>,
+    stream_outputs: Vec<StreamOutput>,
+    triggers: Vec<Trigger>,
+    redact: Vec<String>,
+    protect_noecho: bool,
+    events_file: Option<File>,
+    status_path: Option<PathBuf>,
+    strip_osc: bool,
+    log_header: Option<LogHeaderFormat>,
+    pre_exec: Vec<PreExecHook>,
+    close_fds: bool,
+    pass_fds: Vec<(OwnedFd, RawFd)>,
+    process: ProcessSettings,
+    input_log_file: Option<File>,
+    input_log_tagged: bool,
+    output_buffer_size: usize,
+    output_overflow: OverflowPolicy,
+    input_buffer_size: usize,
+    input_overflow: InputOverflowPolicy,
+    flush_interval: Option<Duration>,
+    fsync: FsyncPolicy,
     script_mode: bool,
     no_flush: bool,
     no_echo: bool,
@@ -213,48 +745,93 @@ struct SpawnOptions {
 /// optional `out` log file.  Additionally it can retrieve instructions from
 /// the given control socket.
 fn spawn(mut opts: SpawnOptions) -> Result<i32, Errno> {
+    // streams are configured late so that the buffer settings apply no matter
+    // if they were set before or after the outputs were added.
+    for mut stream in mem::take(&mut opts.stream_outputs) {
+        stream.configure(opts.output_buffer_size, opts.output_overflow);
+        opts.outputs.push(Output {
+            sink: Box::new(stream),
+            flush: None,
+        });
+    }
+
     // if we can't retrieve the terminal atts we're not directly connected
     // to a pty in which case we won't do any of the terminal related
     // operations.
     let term_attrs = tcgetattr(io::stdin()).ok();
-    let winsize = term_attrs
-        .as_ref()
-        .and_then(|_| get_winsize(io::stdin().as_fd()));
+    let pty = spawn_pty(&mut opts, true)?;
+
+    // If we are not disabling raw, we change to raw mode.  This switches the
+    // terminal to raw mode and restores it on Drop.  As `Drop` is not called
+    // on all ways out, the settings are also restored on panics, on exit and
+    // on fatal signals.  Modes the process left on (such as the alternate
+    // screen) are reset along with it.  In script mode raw mode is never
+    // enabled.
+    let restore_term = if !opts.script_mode && !opts.no_raw {
+        term_attrs
+            .as_ref()
+            .map(|term_attrs| RestoreTerm::enter_raw(term_attrs.clone()))
+    } else {
+        None
+    };
+
+    communication_loop(
+        pty.master,
+        pty.child,
+        term_attrs.is_some(),
+        restore_term.as_ref(),
+        &mut opts,
+        pty.stderr_master,
+    )
+}
+
+/// The parent's end of a process spawned in a pty.
+struct SpawnedPty {
+    master: OwnedFd,
+    stderr_master: Option<OwnedFd>,
+    child: Pid,
+}
+
+/// The window size used for ptys that are not connected to a terminal.
+const DEFAULT_WINSIZE: Winsize = Winsize {
+    ws_row: 24,
+    ws_col: 80,
+    ws_xpixel: 0,
+    ws_ypixel: 0,
+};
+
+/// Creates the pty (or ptys in script mode) and forks the child into it.
+///
+/// If `interactive` is set the ptys are set up after the terminal connected
+/// to our own stdin and stderr, otherwise they start out with default
+/// settings.  This only returns in the parent.  If the child cannot execute
+/// the command, the error is reported back and returned here.
+fn spawn_pty(opts: &mut SpawnOptions, interactive: bool) -> Result<SpawnedPty, Errno> {
+    let term_attrs_and_winsize = |fd: BorrowedFd| {
+        if interactive {
+            let term_attrs = tcgetattr(fd).ok();
+            let winsize = term_attrs.as_ref().and_then(|_| get_winsize(fd));
+            (term_attrs, winsize)
+        } else {
+            (None, Some(DEFAULT_WINSIZE))
+        }
+    };
 
     // Create the outer pty for stdout
+    let (term_attrs, winsize) = term_attrs_and_winsize(io::stdin().as_fd());
     let pty = openpty(&winsize, &term_attrs)?;
+    set_cloexec(pty.master.as_raw_fd())?;
 
     // In script mode we set up a secondary pty.  One could also use `pipe()`
     // here but in that case the `isatty()` call on stderr would report that
     // it's not connected to a tty which is what we want to prevent.
-    let (_restore_term, stderr_pty) = if opts.script_mode {
-        let term_attrs = tcgetattr(io::stderr()).ok();
-        let winsize = term_attrs
-            .as_ref()
-            .and_then(|_| get_winsize(io::stderr().as_fd()));
+    let stderr_pty = if opts.script_mode {
+        let (term_attrs, winsize) = term_attrs_and_winsize(io::stderr().as_fd());
         let stderr_pty = openpty(&winsize, &term_attrs)?;
-        (None, Some(stderr_pty))
-
-    // If we are not disabling raw, we change to raw mode.  This switches the
-    // terminal to raw mode and restores it on Drop.  Unfortunately due to all
-    // our shenanigans here we have no real guarantee that `Drop` is called so
-    // there will be cases where the term is left in raw state and requires a
-    // reset :(
-    } else if !opts.no_raw {
-        (
-            term_attrs.as_ref().map(|term_attrs| {
-                let mut raw_attrs = term_attrs.clone();
-                cfmakeraw(&mut raw_attrs);
-                raw_attrs.local_flags.remove(LocalFlags::ECHO);
-                tcsetattr(io::stdin(), SetArg::TCSAFLUSH, &raw_attrs).ok();
-                RestoreTerm(term_attrs.clone())
-            }),
-            None,
-        )
-
-    // at this point we're neither in scrop mode, nor is raw enabled. do nothing
+        set_cloexec(stderr_pty.master.as_raw_fd())?;
+        Some(stderr_pty)
     } else {
-        (None, None)
+        None
     };
 
     // set some flags after pty has been created.  There are cases where we
@@ -265,34 +842,90 @@ fn spawn(mut opts: SpawnOptions) -> Result<i32, Errno> {
         if opts.script_mode {
             term_attrs.output_flags.remove(OutputFlags::OPOST);
         }
-        if opts.no_echo || (opts.script_mode && !isatty(io::stdin().as_raw_fd()).unwrap_or(false)) {
+        let stdin_is_tty = interactive && isatty(io::stdin().as_raw_fd()).unwrap_or(false);
+        if opts.no_echo || (opts.script_mode && !stdin_is_tty) {
             term_attrs.local_flags.remove(LocalFlags::ECHO);
         }
         tcsetattr(&pty.master, SetArg::TCSAFLUSH, &term_attrs).ok();
     }
 
-    // Fork and establish the communication loop in the parent.  This unfortunately
-    // has to merge stdout/stderr since the pseudo terminal only has one stream for
+    // The arguments are converted before forking to keep the work in the
+    // child small.  It still sets `PAGER` and runs the pre-exec hooks, which
+    // may allocate.
+    let args = opts
+        .command
+        .iter()
+        .filter_map(|x| CString::new(x.as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    // The child reports if it fails to execute the command through this pipe.
+    // Both ends are closed on exec, so the parent reads end of file once the
+    // command was executed.
+    let (error_read, error_write) = pipe_cloexec()?;
+
+    // Fork and hand the pty back to the parent.  This unfortunately has to
+    // merge stdout/stderr since the pseudo terminal only has one stream for
     // both.
     if let ForkResult::Parent { child } = unsafe { fork()? } {
         drop(pty.slave);
-        let stderr_pty = if let Some(stderr_pty) = stderr_pty {
+        drop(error_write);
+        opts.pass_fds.clear();
+        let stderr_master = if let Some(stderr_pty) = stderr_pty {
             drop(stderr_pty.slave);
             Some(stderr_pty.master)
         } else {
             None
         };
-        return communication_loop(
-            pty.master,
+        let mut errno = [0; 4];
+        let mut pos = 0;
+        while pos < errno.len() {
+            match read(error_read.as_raw_fd(), &mut errno[pos..]) {
+                Ok(0) => break,
+                Ok(n) => pos += n,
+                Err(Errno::EINTR) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if pos > 0 {
+            while let Err(Errno::EINTR) = waitpid(child, None) {}
+            return Err(Errno::from_raw(i32::from_ne_bytes(errno)));
+        }
+        return Ok(SpawnedPty {
+            master: pty.master,
+            stderr_master,
             child,
-            term_attrs.is_some(),
-            opts.stdout_file.as_mut(),
-            opts.stdin_file.as_mut(),
-            stderr_pty,
-            !opts.no_flush,
-        );
+        });
     }
 
+    drop(error_read);
+    drop(pty.master);
+    let mut error_write = error_write;
+    let err = match exec_child(
+        opts,
+        pty.slave,
+        stderr_pty.map(|x| x.slave),
+        &args,
+        &mut error_write,
+    ) {
+        Ok(never) => match never {},
+        Err(err) => err,
+    };
+    write(&error_write, &(err as i32).to_ne_bytes()).ok();
+    unsafe { libc::_exit(127) }
+}
+
+/// Turns the forked child into the target executable.
+///
+/// This sets up the tty with `login_tty` which rebinds stdin/stdout/stderr to
+/// the pty, then moves the passed file descriptors in place, closes the others,
+/// applies the process settings and runs the pre-exec hooks.
+fn exec_child(
+    opts: &mut SpawnOptions,
+    slave: OwnedFd,
+    stderr_slave: Option<OwnedFd>,
+    args: &[CString],
+    error_pipe: &mut OwnedFd,
+) -> Result<Infallible, Errno> {
     // set the pagers to `cat` if it's disabled.
     if opts.no_pager || opts.script_mode {
         unsafe {
@@ -300,82 +933,368 @@ fn spawn(mut opts: SpawnOptions) -> Result<i32, Errno> {
         }
     }
 
-    // If we reach this point we're the child and we want to turn into the
-    // target executable after having set up the tty with `login_tty` which
-    // rebinds stdin/stdout/stderr to the pty.
-    let args = opts
-        .command
-        .iter()
-        .filter_map(|x| CString::new(x.as_bytes()).ok())
-        .collect::<Vec<_>>();
-
-    drop(pty.master);
     unsafe {
-        login_tty(pty.slave.into_raw_fd());
-        if let Some(stderr_pty) = stderr_pty {
-            dup2(stderr_pty.slave.into_raw_fd(), io::stderr().as_raw_fd())?;
+        if login_tty(slave.into_raw_fd()) != 0 {
+            return Err(Errno::last());
+        }
+    }
+    if let Some(stderr_slave) = stderr_slave {
+        dup2(stderr_slave.as_raw_fd(), io::stderr().as_raw_fd())?;
+    }
+
+    // The passed file descriptors (and the error pipe) are first moved above
+    // all targets so that placing one of them can't clobber another one.
+    let min_fd = opts.pass_fds.iter().map(|x| x.1).max().unwrap_or(2) + 1;
+    let to_move = opts.pass_fds.iter_mut().map(|x| &mut x.0);
+    for fd in to_move.chain(Some(error_pipe)) {
+        let moved = fcntl(fd.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(min_fd))?;
+        *fd = unsafe { OwnedFd::from_raw_fd(moved) };
+    }
+    for (fd, target) in &opts.pass_fds {
+        dup2(fd.as_raw_fd(), *target)?;
+    }
+
+    if opts.close_fds {
+        let mut first = 3;
+        loop {
+            let next_kept = opts
+                .pass_fds
+                .iter()
+                .map(|x| x.1)
+                .filter(|&x| x >= first)
+                .min();
+            set_cloexec_range(first, next_kept.map_or(RawFd::MAX, |x| x - 1));
+            match next_kept {
+                Some(fd) if fd < RawFd::MAX => first = fd + 1,
+                _ => break,
+            }
         }
     }
 
-    // Since this returns Infallible rather than ! due to limitations, we need
-    // this dummy match.
-    match execvp(&args[0], &args)? {}
+    opts.process.apply()?;
+
+    for hook in opts.pre_exec.iter_mut() {
+        hook().map_err(|err| Errno::from_raw(err.raw_os_error().unwrap_or(libc::EINVAL)))?;
+    }
+
+    execvp(&args[0], args)
+}
+
+/// Creates a pipe with both ends closed on exec.
+#[cfg(target_os = "linux")]
+fn pipe_cloexec() -> Result<(OwnedFd, OwnedFd), Errno> {
+    nix::unistd::pipe2(OFlag::O_CLOEXEC)
+}
+
+/// Creates a pipe with both ends closed on exec.
+///
+/// Without `pipe2` another thread could fork before the flags are set.
+#[cfg(not(target_os = "linux"))]
+fn pipe_cloexec() -> Result<(OwnedFd, OwnedFd), Errno> {
+    let (read, write) = nix::unistd::pipe()?;
+    set_cloexec(read.as_raw_fd())?;
+    set_cloexec(write.as_raw_fd())?;
+    Ok((read, write))
+}
+
+/// Checks if the file descriptor is a pipe or FIFO.
+fn is_pipe(fd: BorrowedFd) -> bool {
+    fstat(fd.as_raw_fd()).map_or(false, |stat| {
+        SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFIFO
+    })
+}
+
+/// Switches a file descriptor to non blocking mode.
+fn set_nonblocking(fd: BorrowedFd) -> Result<(), Errno> {
+    let flags = OFlag::from_bits_truncate(fcntl(fd.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    Ok(())
+}
+
+/// Marks a file descriptor to be closed on exec.
+fn set_cloexec(fd: RawFd) -> Result<(), Errno> {
+    let flags = FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD)?);
+    fcntl(fd, FcntlArg::F_SETFD(flags | FdFlag::FD_CLOEXEC))?;
+    Ok(())
+}
+
+/// Marks all open file descriptors from `first` to `last` (inclusive) to be
+/// closed on exec.
+// libc provides `c_uint` and `c_long` for older compilers too
+#[allow(clippy::incompatible_msrv)]
+fn set_cloexec_range(first: RawFd, last: RawFd) {
+    if first > last {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // close_range is only available on Linux 5.11 and later, otherwise
+        // this falls back to going through the file descriptors one by one.
+        let rv = unsafe {
+            libc::syscall(
+                libc::SYS_close_range,
+                first as libc::c_uint,
+                last as libc::c_uint,
+                libc::CLOSE_RANGE_CLOEXEC,
+            )
+        };
+        if rv == 0 {
+            return;
+        }
+    }
+    let max_fd = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        n if n > 0 => n.min(RawFd::MAX as libc::c_long) as RawFd,
+        _ => 1024,
+    };
+    for fd in first..=last.min(max_fd - 1) {
+        set_cloexec(fd).ok();
+    }
 }
 
 fn communication_loop(
     master: OwnedFd,
     child: Pid,
     is_tty: bool,
-    mut out_file: Option<&mut File>,
-    in_file: Option<&mut File>,
+    restore_term: Option<&RestoreTerm>,
+    opts: &mut SpawnOptions,
     stderr: Option<OwnedFd>,
-    flush: bool,
 ) -> Result<i32, Errno> {
+    let mut session = Session::new(
+        opts.events_file.is_some() || opts.status_path.is_some(),
+        child,
+    );
+    let logger = Logger::start(
+        mem::take(&mut opts.outputs),
+        opts.input_log_file
+            .take()
+            .map(|file| InputLog::new(file, opts.input_log_tagged)),
+        SessionLog {
+            events: opts.events_file.take().map(BufWriter::new),
+            status_path: opts.status_path.clone(),
+        },
+        LogConfig {
+            flush: !opts.no_flush,
+            flush_interval: opts.flush_interval,
+            fsync: opts.fsync,
+            redact: redact::compile(&opts.redact),
+        },
+    );
+    let started = Instant::now();
+    if let Some(format) = opts.log_header {
+        let winsize = get_winsize(master.as_fd());
+        logger.output_lines(&log_header(
+            format,
+            &RunInfo {
+                command: &opts.command,
+                pid: child,
+                cols: winsize.map_or(0, |x| x.ws_col),
+                rows: winsize.map_or(0, |x| x.ws_row),
+            },
+        ));
+    }
+    // input is written without blocking, the rest stays queued until the pty
+    // is writable again.
+    set_nonblocking(master.as_fd())?;
+    if let Some(ref fd) = stderr {
+        set_nonblocking(fd.as_fd())?;
+    }
+    let new_queue = || InputQueue::new(opts.input_buffer_size, opts.input_overflow);
+    let mut stdin_queue = new_queue();
+    let mut trigger_queue = new_queue();
+    let mut sources = mem::take(&mut opts.sources)
+        .into_iter()
+        .map(|source| QueuedSource {
+            source,
+            queue: new_queue(),
+            done: false,
+        })
+        .collect::<Vec<_>>();
+    // end of file is sent once all queued input was written
+    let mut pending_eof = false;
+    let mut triggers = Triggers::new(mem::take(&mut opts.triggers));
+    let mut noecho = NoechoGuard {
+        enabled: opts.protect_noecho,
+        hidden: false,
+    };
+    let mut stdout_osc = None;
+    let mut stderr_osc = None;
+    if session.is_enabled() || opts.strip_osc {
+        stdout_osc = Some(OscParser::new(opts.strip_osc));
+        stderr_osc = Some(OscParser::new(opts.strip_osc));
+    }
+    let mut modes = if restore_term.is_some() {
+        Some(ModeTracker::new())
+    } else {
+        None
+    };
     let mut buf = [0; 4096];
+    let mut master_buf = ReadBuf::new();
+    let mut stderr_buf = ReadBuf::new();
+    // output nobody looks at is spliced into stdout if it's a pipe
+    let mut splice_stdout = cfg!(target_os = "linux")
+        && !logger.logs_output()
+        && triggers.is_empty()
+        && stdout_osc.is_none()
+        && modes.is_none()
+        && is_pipe(io::stdout().as_fd());
     let mut read_stdin = true;
+    // set once stdin reached its end and we wait for the sources to be done
+    let mut draining = false;
     let mut done = false;
+    let mut child_status = None;
     let stdin = io::stdin();
 
-    let got_winch = Arc::new(AtomicBool::new(false));
-    if is_tty {
-        signal_hook::flag::register(SIGWINCH, Arc::clone(&got_winch)).ok();
-    }
+    let mut signals = SignalPipe::new().map_err(io_to_errno)?;
+    let got_winch = if is_tty {
+        Some(signals.register(SIGWINCH).map_err(io_to_errno)?)
+    } else {
+        None
+    };
+    let job_control = if is_tty {
+        Some((
+            signals.register(SIGTSTP).map_err(io_to_errno)?,
+            signals.register(SIGCONT).map_err(io_to_errno)?,
+        ))
+    } else {
+        None
+    };
+    let got_chld = signals.register(SIGCHLD).map_err(io_to_errno)?;
+    // the child might have exited before the handler was registered
+    got_chld.store(true, Ordering::Relaxed);
+    let mut drain_deadline = None;
 
     while !done {
-        if got_winch.load(Ordering::Relaxed) {
+        if let Some(event) = session.check_foreground(master.as_fd()) {
+            logger.event(event);
+        }
+        if got_winch
+            .as_ref()
+            .map_or(false, |x| x.swap(false, Ordering::Relaxed))
+        {
             forward_winsize(master.as_fd(), stderr.as_ref().map(|x| x.as_fd()))?;
-            got_winch.store(false, Ordering::Relaxed);
+        }
+        if let Some((ref got_tstp, ref got_cont)) = job_control {
+            let mut resumed = got_cont.swap(false, Ordering::Relaxed);
+            if got_tstp.swap(false, Ordering::Relaxed) {
+                suspend(master.as_fd(), child, restore_term);
+                got_cont.store(false, Ordering::Relaxed);
+                resumed = true;
+            }
+            if resumed {
+                resume(
+                    master.as_fd(),
+                    stderr.as_ref().map(|x| x.as_fd()),
+                    child,
+                    restore_term,
+                )?;
+            }
+        }
+        if got_chld.swap(false, Ordering::Relaxed) && child_status.is_none() {
+            // the child is reaped here but we keep going until the pty is
+            // closed so that we do not lose any output.
+            child_status = match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+                Ok(status) => exit_code(status),
+                Err(_) => None,
+            };
+            // unless we wait for all processes, only the output that is
+            // already on its way is drained.
+            if child_status.is_some() && opts.child_exit != ChildExitPolicy::Wait {
+                if opts.child_exit == ChildExitPolicy::Kill {
+                    kill_all(master.as_fd(), child);
+                }
+                drain_deadline = Some(Instant::now() + DRAIN_TIMEOUT);
+            }
         }
 
-        let mut read_fds = FdSet::new();
-        let mut timeout = TimeVal::new(1, 0);
-        read_fds.insert(master.as_fd());
+        if drain_deadline.map_or(false, |x| Instant::now() >= x) {
+            break;
+        }
         if !read_stdin && is_tty {
             read_stdin = true;
         }
-        if read_stdin {
-            read_fds.insert(stdin.as_fd());
+        let input_pending = !stdin_queue.is_empty()
+            || !trigger_queue.is_empty()
+            || sources.iter().any(|x| !x.queue.is_empty());
+        let poll_stdin = read_stdin && stdin_queue.read_limit(buf.len()) > 0;
+        let polled_sources = sources
+            .iter()
+            .map(|x| !x.done && x.queue.read_limit(buf.len()) > 0)
+            .collect::<Vec<_>>();
+        let mut fds = vec![
+            (signals.as_fd(), PollFlags::POLLIN),
+            (
+                master.as_fd(),
+                if input_pending {
+                    PollFlags::POLLIN | PollFlags::POLLOUT
+                } else {
+                    PollFlags::POLLIN
+                },
+            ),
+        ];
+        if let Some(ref fd) = stderr {
+            fds.push((fd.as_fd(), PollFlags::POLLIN));
         }
-        if let Some(ref f) = in_file {
-            read_fds.insert(f.as_fd());
+        if poll_stdin {
+            fds.push((stdin.as_fd(), PollFlags::POLLIN));
         }
-        if let Some(ref fd) = stderr {
-            read_fds.insert(fd.as_fd());
+        for (source, _) in sources.iter().zip(&polled_sources).filter(|x| *x.1) {
+            fds.push((source.source.as_fd(), PollFlags::POLLIN));
         }
-        match select(None, Some(&mut read_fds), None, None, Some(&mut timeout)) {
-            Ok(0) | Err(Errno::EINTR | Errno::EAGAIN) => continue,
-            Ok(_) => {}
-            Err(err) => return Err(err),
+        let mut timeout = session.next_check();
+        if let Some(deadline) = drain_deadline {
+            let drain = deadline
+                .saturating_duration_since(Instant::now())
+                .min(DRAIN_IDLE_TIMEOUT);
+            timeout = Some(timeout.map_or(drain, |x| x.min(drain)));
         }
+        let revents = match wait_ready(&fds, timeout) {
+            Ok(revents) if drain_deadline.is_some() && revents.iter().all(|x| x.is_empty()) => {
+                break
+            }
+            Ok(revents) => revents,
+            Err(Errno::EINTR | Errno::EAGAIN) => continue,
+            Err(err) => return Err(err),
+        };
+        let mut ready = revents
+            .iter()
+            .map(|x| x.intersects(PollFlags::POLLIN | PollFlags::POLLHUP | PollFlags::POLLERR));
+        let signal_ready = ready.next().unwrap_or(false);
+        let master_ready = ready.next().unwrap_or(false);
+        let stderr_ready = stderr.is_some() && ready.next().unwrap_or(false);
+        let stdin_ready = poll_stdin && ready.next().unwrap_or(false);
+        let sources_ready = polled_sources
+            .iter()
+            .map(|&polled| polled && ready.next().unwrap_or(false))
+            .collect::<Vec<_>>();
 
-        if read_fds.contains(stdin.as_fd()) {
-            match read(stdin.as_raw_fd(), &mut buf) {
+        if signal_ready {
+            signals.drain();
+        }
+        if stdin_ready {
+            let limit = stdin_queue.read_limit(buf.len());
+            match read(stdin.as_raw_fd(), &mut buf[..limit]) {
                 Ok(0) => {
-                    send_eof_sequence(master.as_fd());
+                    match opts.stdin_eof {
+                        StdinEofPolicy::Eof => pending_eof = true,
+                        StdinEofPolicy::Ignore => {}
+                        StdinEofPolicy::Hangup => hangup(master.as_fd(), child),
+                        StdinEofPolicy::Wait => {
+                            if !draining {
+                                draining = true;
+                                for source in sources.iter_mut() {
+                                    source.source.close();
+                                }
+                            }
+                            if sources.is_empty() {
+                                pending_eof = true;
+                            }
+                        }
+                    }
                     read_stdin = false;
                 }
                 Ok(n) => {
-                    write_all(master.as_fd(), &buf[..n])?;
+                    let input = stdin_queue.push(&buf[..n])?;
+                    noecho.log_input(&logger, master.as_fd(), "stdin", input);
                 }
                 Err(Errno::EINTR | Errno::EAGAIN) => {}
                 // on linux a closed tty raises EIO
@@ -385,84 +1304,435 @@ fn communication_loop(
                 Err(err) => return Err(err),
             };
         }
-        if let Some(ref f) = in_file {
-            if read_fds.contains(f.as_fd()) {
-                // use read() here so that we can handle EAGAIN/EINTR
-                // without this we might receive resource temporary unavailable
+        for (source, is_ready) in sources.iter_mut().zip(sources_ready) {
+            if is_ready {
+                // EAGAIN/EINTR must not abort here, otherwise we might fail
+                // with resource temporary unavailable
                 // see https://github.com/mitsuhiko/teetty/issues/3
-                match read(f.as_raw_fd(), &mut buf) {
-                    Ok(0) | Err(Errno::EAGAIN | Errno::EINTR) => {}
-                    Err(err) => return Err(err),
+                let limit = source.queue.read_limit(buf.len());
+                match source.source.read(&mut buf[..limit]) {
+                    Ok(0) => source.done = true,
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                        ) => {}
+                    Err(err) => return Err(io_to_errno(err)),
                     Ok(n) => {
-                        write_all(master.as_fd(), &buf[..n])?;
+                        let input = source.queue.push(&buf[..n])?;
+                        noecho.log_input(&logger, master.as_fd(), source.source.name(), input);
                     }
                 }
             }
         }
         if let Some(ref fd) = stderr {
-            if read_fds.contains(fd.as_fd()) {
-                match read(fd.as_raw_fd(), &mut buf) {
-                    Ok(0) | Err(_) => {}
-                    Ok(n) => {
-                        forward_and_log(io::stderr().as_fd(), &mut out_file, &buf[..n], flush)?;
+            if stderr_ready {
+                match stderr_buf.fill(fd.as_fd()) {
+                    Ok([]) | Err(_) => {}
+                    Ok(output) => {
+                        forward_output(
+                            io::stderr().as_fd(),
+                            output,
+                            &logger,
+                            stderr_osc.as_mut(),
+                            &mut session,
+                        )?;
+                        triggers.feed(true, output, |response| {
+                            send_response(
+                                master.as_fd(),
+                                &logger,
+                                &mut noecho,
+                                &mut trigger_queue,
+                                response,
+                            )
+                        })?;
                     }
                 }
             }
         }
-        if read_fds.contains(master.as_fd()) {
-            match read(master.as_raw_fd(), &mut buf) {
-                // on linux a closed tty raises EIO
+        if master_ready && splice_stdout {
+            match splice_output(master.as_fd(), io::stdout().as_fd()) {
                 Ok(0) | Err(Errno::EIO) => {
                     done = true;
                 }
-                Ok(n) => forward_and_log(io::stdout().as_fd(), &mut out_file, &buf[..n], flush)?,
+                Ok(_) | Err(Errno::EAGAIN | Errno::EINTR) => {}
+                // the kernel cannot splice from a pty, read it instead
+                Err(Errno::EINVAL) => splice_stdout = false,
+                Err(err) => return Err(err),
+            }
+        } else if master_ready {
+            match master_buf.fill(master.as_fd()) {
+                // on linux a closed tty raises EIO
+                Ok([]) | Err(Errno::EIO) => {
+                    done = true;
+                }
+                Ok(output) => {
+                    forward_output(
+                        io::stdout().as_fd(),
+                        output,
+                        &logger,
+                        stdout_osc.as_mut(),
+                        &mut session,
+                    )?;
+                    if let Some(ref mut modes) = modes {
+                        modes.feed(output);
+                    }
+                    triggers.feed(false, output, |response| {
+                        send_response(
+                            master.as_fd(),
+                            &logger,
+                            &mut noecho,
+                            &mut trigger_queue,
+                            response,
+                        )
+                    })?;
+                }
                 Err(Errno::EAGAIN | Errno::EINTR) => {}
                 Err(err) => return Err(err),
             };
         }
+
+        if !done {
+            let written = stdin_queue
+                .write_to(master.as_fd())
+                .and_then(|()| trigger_queue.write_to(master.as_fd()))
+                .and_then(|()| {
+                    sources
+                        .iter_mut()
+                        .try_for_each(|x| x.queue.write_to(master.as_fd()))
+                });
+            match written {
+                Ok(()) => {}
+                // the pty was closed, which the next read picks up
+                Err(Errno::EIO) => {}
+                Err(err) => return Err(err),
+            }
+            let before = sources.len();
+            sources.retain(|x| !x.done || !x.queue.is_empty());
+            if draining && before > 0 && sources.is_empty() {
+                pending_eof = true;
+            }
+            if pending_eof
+                && stdin_queue.is_empty()
+                && trigger_queue.is_empty()
+                && sources.iter().all(|x| x.queue.is_empty())
+            {
+                pending_eof = false;
+                send_eof_sequence(master.as_fd());
+            }
+        }
     }
 
-    Ok(match waitpid(child, None)? {
-        WaitStatus::Exited(_, status) => status,
-        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
-        _ => 1,
-    })
+    for parser in [stdout_osc, stderr_osc].iter_mut().flatten() {
+        let mut rest = Vec::new();
+        parser.flush(&mut rest);
+        if !rest.is_empty() {
+            logger.output(&rest);
+        }
+    }
+
+    let code = match child_status {
+        Some(status) => status,
+        None => exit_code(waitpid(child, None)?).unwrap_or(1),
+    };
+    if let Some(event) = session.exit(code) {
+        logger.event(event);
+    }
+    if let Some(format) = opts.log_header {
+        logger.output_lines(&log_footer(format, code, started.elapsed()));
+    }
+    logger.finish().map_err(io_to_errno)?;
+
+    Ok(code)
 }
 
-fn forward_and_log(
+/// Converts a wait status into an exit code.
+///
+/// Returns `None` if the process did not terminate.
+fn exit_code(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, status) => Some(status),
+        WaitStatus::Signaled(_, signal, _) => Some(128 + signal as i32),
+        WaitStatus::StillAlive => None,
+        _ => Some(1),
+    }
+}
+
+/// Wakes up the communication loop when signals arrive.
+///
+/// Every registered signal sets a flag and writes into a self-pipe which is
+/// polled together with all other fds.  The previous handlers are restored
+/// on drop.
+struct SignalPipe {
+    reader: UnixStream,
+    writer: UnixStream,
+    hooks: SignalHooks,
+}
+
+impl SignalPipe {
+    fn new() -> Result<SignalPipe, io::Error> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+        Ok(SignalPipe {
+            reader,
+            writer,
+            hooks: SignalHooks::new(),
+        })
+    }
+
+    /// Registers a signal and returns the flag that is set when it arrives.
+    #[allow(clippy::incompatible_msrv)]
+    fn register(&mut self, signal: c_int) -> Result<Arc<AtomicBool>, io::Error> {
+        let flag = Arc::new(AtomicBool::new(false));
+        self.hooks.register(signal, || {
+            signal_hook::flag::register(signal, Arc::clone(&flag))
+        })?;
+        let writer = self.writer.try_clone()?;
+        self.hooks.register(signal, || {
+            signal_hook::low_level::pipe::register(signal, writer)
+        })?;
+        Ok(flag)
+    }
+
+    /// Reads everything from the pipe.
+    fn drain(&self) {
+        let mut buf = [0; 64];
+        while matches!((&self.reader).read(&mut buf), Ok(n) if n > 0) {}
+    }
+}
+
+impl AsFd for SignalPipe {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.reader.as_fd()
+    }
+}
+
+/// Suspends the child and ourselves.
+///
+/// The terminal is restored first and the foreground process group of the
+/// pty is stopped.  This returns once we are continued.
+fn suspend(master: BorrowedFd, child: Pid, restore_term: Option<&RestoreTerm>) {
+    if let Some(restore_term) = restore_term {
+        restore_term.suspend();
+    }
+    killpg(foreground_pgrp(master).unwrap_or(child), Signal::SIGSTOP).ok();
+    raise(Signal::SIGSTOP).ok();
+}
+
+/// Picks up again after we were continued.
+///
+/// This switches back to raw mode, forwards the window size which might have
+/// changed in the meantime and continues the child.
+fn resume(
+    master: BorrowedFd,
+    stderr_master: Option<BorrowedFd>,
+    child: Pid,
+    restore_term: Option<&RestoreTerm>,
+) -> Result<(), Errno> {
+    if let Some(restore_term) = restore_term {
+        restore_term.resume();
+    }
+    forward_winsize(master, stderr_master)?;
+    killpg(foreground_pgrp(master).unwrap_or(child), Signal::SIGCONT).ok();
+    Ok(())
+}
+
+/// Waits until one of the file descriptors is ready or the timeout passed.
+///
+/// Returns the events that occurred for each file descriptor.
+#[cfg(not(target_os = "macos"))]
+fn wait_ready(
+    fds: &[(BorrowedFd, PollFlags)],
+    timeout: Option<Duration>,
+) -> Result<Vec<PollFlags>, Errno> {
+    let mut poll_fds = fds
+        .iter()
+        .map(|&(fd, events)| PollFd::new(fd, events))
+        .collect::<Vec<_>>();
+    let timeout = timeout.map_or(PollTimeout::NONE, |x| {
+        PollTimeout::try_from(x).unwrap_or(PollTimeout::MAX)
+    });
+    poll(&mut poll_fds, timeout)?;
+    Ok(poll_fds
+        .iter()
+        .map(|x| x.revents().unwrap_or_else(PollFlags::empty))
+        .collect())
+}
+
+/// Waits until one of the file descriptors is ready or the timeout passed.
+///
+/// macOS does not support `poll` on ttys (it reports `POLLNVAL`), so `select`
+/// is used instead.  This limits file descriptors to below `FD_SETSIZE`.
+#[cfg(target_os = "macos")]
+fn wait_ready(
+    fds: &[(BorrowedFd, PollFlags)],
+    timeout: Option<Duration>,
+) -> Result<Vec<PollFlags>, Errno> {
+    use nix::sys::select::{select, FdSet, FD_SETSIZE};
+    use nix::sys::time::TimeVal;
+
+    let mut read_fds = FdSet::new();
+    let mut write_fds = FdSet::new();
+    for &(fd, events) in fds {
+        if fd.as_raw_fd() as usize >= FD_SETSIZE {
+            return Err(Errno::EBADF);
+        }
+        if events.contains(PollFlags::POLLIN) {
+            read_fds.insert(fd);
+        }
+        if events.contains(PollFlags::POLLOUT) {
+            write_fds.insert(fd);
+        }
+    }
+    let mut timeout = timeout.map(|x| {
+        TimeVal::new(
+            x.as_secs().min(i32::MAX as u64) as _,
+            x.subsec_micros() as _,
+        )
+    });
+    select(None, &mut read_fds, &mut write_fds, None, timeout.as_mut())?;
+    Ok(fds
+        .iter()
+        .map(|&(fd, _)| {
+            let mut revents = PollFlags::empty();
+            if read_fds.contains(fd) {
+                revents |= PollFlags::POLLIN;
+            }
+            if write_fds.contains(fd) {
+                revents |= PollFlags::POLLOUT;
+            }
+            revents
+        })
+        .collect())
+}
+
+/// Forwards output to the terminal and hands it to the logs.
+///
+/// If OSC sequences are parsed, the session is updated along the way.
+fn forward_output(
     fd: BorrowedFd,
-    out_file: &mut Option<&mut File>,
     buf: &[u8],
-    flush: bool,
+    logger: &Logger,
+    osc: Option<&mut OscParser>,
+    session: &mut Session,
 ) -> Result<(), Errno> {
-    if let Some(logfile) = out_file {
-        logfile.write_all(buf).map_err(|x| match x.raw_os_error() {
-            Some(errno) => Errno::from_raw(errno),
-            None => Errno::EINVAL,
-        })?;
-        if flush {
-            logfile.flush().ok();
+    write_all(fd, buf)?;
+    match osc {
+        Some(parser) => {
+            let mut log_buf = Vec::with_capacity(buf.len());
+            parser.feed(buf, &mut log_buf, |code, data| {
+                if let Some(event) = session.osc(code, data) {
+                    logger.event(event);
+                }
+            });
+            if !log_buf.is_empty() {
+                logger.output(&log_buf);
+            }
         }
+        None => logger.output(buf),
     }
-    write_all(fd, buf)?;
     Ok(())
 }
 
+/// Sends the response of a trigger to the terminal as input.
+fn send_response(
+    master: BorrowedFd,
+    logger: &Logger,
+    noecho: &mut NoechoGuard,
+    queue: &mut InputQueue,
+    response: &[u8],
+) -> Result<(), Errno> {
+    noecho.log_input(logger, master, "trigger", response);
+    queue.push_always(response);
+    Ok(())
+}
+
+/// An input source with the input that was not written yet.
+struct QueuedSource {
+    source: Box<dyn InputSource>,
+    queue: InputQueue,
+    /// Set once the source reached its end.
+    done: bool,
+}
+
+/// Keeps input out of the input log while the terminal does not echo it.
+///
+/// Programs that ask for passwords disable echo while leaving the terminal in
+/// canonical mode.  Instead of the input, a marker is logged once per such
+/// period.
+struct NoechoGuard {
+    enabled: bool,
+    hidden: bool,
+}
+
+impl NoechoGuard {
+    fn log_input(&mut self, logger: &Logger, master: BorrowedFd, source: &str, buf: &[u8]) {
+        if self.enabled && is_noecho(master) {
+            if !self.hidden {
+                self.hidden = true;
+                logger.input_marker(source, "[input hidden]");
+            }
+        } else {
+            self.hidden = false;
+            logger.input(source, buf);
+        }
+    }
+}
+
+/// Checks if the terminal is in canonical mode with echo disabled.
+fn is_noecho(fd: BorrowedFd) -> bool {
+    match tcgetattr(fd) {
+        Ok(attrs) => {
+            attrs.local_flags.contains(LocalFlags::ICANON)
+                && !attrs.local_flags.contains(LocalFlags::ECHO)
+        }
+        // if we cannot tell, err on the side of caution
+        Err(_) => true,
+    }
+}
+
+fn io_to_errno(err: io::Error) -> Errno {
+    match err.raw_os_error() {
+        Some(errno) => Errno::from_raw(errno),
+        None => Errno::EINVAL,
+    }
+}
+
 /// Forwards the winsize and emits SIGWINCH
 fn forward_winsize(master: BorrowedFd, stderr_master: Option<BorrowedFd>) -> Result<(), Errno> {
     if let Some(winsize) = get_winsize(io::stdin().as_fd()) {
-        set_winsize(master, winsize).ok();
-        if let Some(second_master) = stderr_master {
-            set_winsize(second_master, winsize).ok();
-        }
-        if let Ok(pgrp) = tcgetpgrp(master) {
-            killpg(pgrp, Signal::SIGWINCH).ok();
-        }
+        resize_pty(master, stderr_master, winsize).ok();
+    }
+    Ok(())
+}
+
+/// Sets the winsize of the pty (and stderr pty) and emits SIGWINCH
+fn resize_pty(
+    master: BorrowedFd,
+    stderr_master: Option<BorrowedFd>,
+    winsize: Winsize,
+) -> Result<(), Errno> {
+    set_winsize(master, winsize)?;
+    if let Some(second_master) = stderr_master {
+        set_winsize(second_master, winsize).ok();
+    }
+    if let Ok(pgrp) = tcgetpgrp(master) {
+        killpg(pgrp, Signal::SIGWINCH).ok();
     }
     Ok(())
 }
 
+/// Returns the size of the terminal connected to stdout as columns and rows.
+///
+/// If stdout is not a terminal, `None` is returned.
+pub fn terminal_size() -> Option<(u16, u16)> {
+    get_winsize(io::stdout().as_fd()).map(|x| (x.ws_col, x.ws_row))
+}
+
 /// If possible, returns the terminal size of the given fd.
+// the ioctl macros of nix expand to the libc type aliases
+#[allow(clippy::incompatible_msrv)]
 fn get_winsize(fd: BorrowedFd) -> Option<Winsize> {
     nix::ioctl_read_bad!(_get_window_size, TIOCGWINSZ, Winsize);
     let mut size: Winsize = unsafe { std::mem::zeroed() };
@@ -471,6 +1741,7 @@ fn get_winsize(fd: BorrowedFd) -> Option<Winsize> {
 }
 
 /// Sets the winsize
+#[allow(clippy::incompatible_msrv)]
 fn set_winsize(fd: BorrowedFd, winsize: Winsize) -> Result<(), Errno> {
     nix::ioctl_write_ptr_bad!(_set_window_size, TIOCSWINSZ, Winsize);
     unsafe { _set_window_size(fd.as_raw_fd(), &winsize) }?;
@@ -486,6 +1757,33 @@ fn send_eof_sequence(fd: BorrowedFd) {
     }
 }
 
+/// Hangs up the process and the foreground process group of the pty.
+fn hangup(master: BorrowedFd, child: Pid) {
+    if let Some(pgrp) = foreground_pgrp(master) {
+        killpg(pgrp, Signal::SIGHUP).ok();
+    }
+    kill(child, Signal::SIGHUP).ok();
+}
+
+/// Kills the process group of the child and the foreground process group of
+/// the pty.
+fn kill_all(master: BorrowedFd, child: Pid) {
+    if let Some(pgrp) = foreground_pgrp(master) {
+        killpg(pgrp, Signal::SIGKILL).ok();
+    }
+    killpg(child, Signal::SIGKILL).ok();
+}
+
+/// Returns the foreground process group of the pty.
+///
+/// If there is none, `tcgetpgrp` reports 0 which must not be passed to
+/// `killpg` as that would signal our own process group.
+fn foreground_pgrp(master: BorrowedFd) -> Option<Pid> {
+    tcgetpgrp(master)
+        .ok()
+        .filter(|x| x.as_raw() > 0 && *x != getpgrp())
+}
+
 /// Calls write in a loop until it's done.
 fn write_all(fd: BorrowedFd, mut buf: &[u8]) -> Result<(), Errno> {
     while !buf.is_empty() {
@@ -496,6 +1794,19 @@ fn write_all(fd: BorrowedFd, mut buf: &[u8]) -> Result<(), Errno> {
     Ok(())
 }
 
+/// Opens a file for logging, either truncating or appending.
+fn open_log_file(path: &Path, truncate: bool) -> Result<File, io::Error> {
+    if !truncate {
+        File::options().append(true).create(true).open(path)
+    } else {
+        File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path)
+    }
+}
+
 /// Creates a FIFO at the path if the file does not exist yet.
 fn mkfifo_atomic(path: &Path) -> Result<(), Errno> {
     match mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR) {
@@ -503,11 +1814,3 @@ fn mkfifo_atomic(path: &Path) -> Result<(), Errno> {
         Err(err) => Err(err),
     }
 }
-
-struct RestoreTerm(Termios);
-
-impl Drop for RestoreTerm {
-    fn drop(&mut self) {
-        tcsetattr(io::stdin(), SetArg::TCSAFLUSH, &self.0).ok();
-    }
-}
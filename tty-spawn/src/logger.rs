@@ -0,0 +1,501 @@
+//! The log writer.
+//!
+//! Everything that goes into output files, FIFOs, sockets and the input log is
+//! written on a dedicated thread.  The communication loop only hands the data
+//! over through a bounded channel so that slow log storage does not hold up the
+//! terminal.  Chunks that queue up in the channel are coalesced into a single
+//! write.  Secrets are redacted here too, so they never reach a log but the
+//! terminal output stays untouched.
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use regex::bytes::Regex;
+
+use crate::redact::Redactor;
+use crate::session::Event;
+use crate::sink::OutputSink;
+
+/// How many messages can be queued up before the terminal is held up.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// The maximum number of bytes coalesced into one write.
+const MAX_COALESCE: usize = 256 * 1024;
+
+/// How often sinks with pending output are retried.
+const STREAM_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait for more data before output held back for redaction is
+/// written out regardless.
+const REDACT_IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Controls when log files are synced to disk with `fsync`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// Never sync explicitly and leave it to the operating system.
+    #[default]
+    Never,
+    /// Sync after every flush.
+    Always,
+    /// Sync at most once per interval and at the end.
+    Interval(Duration),
+}
+
+pub(crate) struct Output {
+    pub sink: Box<dyn OutputSink>,
+    pub flush: Option<bool>,
+}
+
+impl Output {
+    fn write(&mut self, buf: &[u8], flush: bool) -> Result<(), io::Error> {
+        self.sink.write(buf)?;
+        if self.flush.unwrap_or(flush) {
+            self.sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Records input into the input log.
+pub(crate) struct InputLog {
+    file: BufWriter<File>,
+    tagged: bool,
+    at_line_start: bool,
+}
+
+impl InputLog {
+    pub fn new(file: File, tagged: bool) -> InputLog {
+        InputLog {
+            file: BufWriter::new(file),
+            tagged,
+            at_line_start: true,
+        }
+    }
+
+    fn log(&mut self, source: &str, buf: &[u8]) -> Result<(), io::Error> {
+        if self.tagged {
+            let ts = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            writeln!(
+                self.file,
+                "{}.{:03} {} {}",
+                ts.as_secs(),
+                ts.subsec_millis(),
+                source,
+                buf.escape_ascii()
+            )
+        } else {
+            self.at_line_start = buf.ends_with(b"\n");
+            self.file.write_all(buf)
+        }
+    }
+
+    /// Logs a marker in place of input, on a line of its own.
+    fn marker(&mut self, source: &str, marker: &str) -> Result<(), io::Error> {
+        if self.tagged {
+            return self.log(source, marker.as_bytes());
+        }
+        if !self.at_line_start {
+            self.file.write_all(b"\n")?;
+        }
+        self.at_line_start = true;
+        writeln!(self.file, "{}", marker)
+    }
+}
+
+/// Where session events and the session status go.
+pub(crate) struct SessionLog {
+    pub events: Option<BufWriter<File>>,
+    pub status_path: Option<PathBuf>,
+}
+
+impl SessionLog {
+    fn is_empty(&self) -> bool {
+        self.events.is_none() && self.status_path.is_none()
+    }
+
+    fn record(&mut self, event: &Event) -> Result<(), io::Error> {
+        if let Some(ref mut events) = self.events {
+            writeln!(events, "{}", event.line)?;
+            events.flush()?;
+        }
+        // the status is replaced atomically so readers never see partial
+        // writes.
+        if let Some(ref path) = self.status_path {
+            let mut tmp = path.clone().into_os_string();
+            tmp.push(".tmp");
+            fs::write(&tmp, format!("{}\n", event.status))?;
+            fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct LogConfig {
+    pub flush: bool,
+    pub flush_interval: Option<Duration>,
+    pub fsync: FsyncPolicy,
+    pub redact: Option<Regex>,
+}
+
+enum LogMessage {
+    Output(Vec<u8>),
+    Input(String, Vec<u8>),
+    InputMarker(String, &'static str),
+    Event(Event),
+}
+
+/// Handle to the log writer thread.
+pub(crate) struct Logger {
+    sender: Option<SyncSender<LogMessage>>,
+    thread: Option<JoinHandle<Result<(), io::Error>>>,
+    log_output: bool,
+    log_input: bool,
+    log_events: bool,
+    at_line_start: Cell<bool>,
+}
+
+impl Logger {
+    /// Starts the log writer thread.
+    ///
+    /// If there is nothing to log, no thread is started.
+    pub fn start(
+        outputs: Vec<Output>,
+        input_log: Option<InputLog>,
+        session_log: SessionLog,
+        config: LogConfig,
+    ) -> Logger {
+        let log_output = !outputs.is_empty();
+        let log_input = input_log.is_some();
+        let log_events = !session_log.is_empty();
+        if !log_output && !log_input && !log_events {
+            return Logger {
+                sender: None,
+                thread: None,
+                log_output,
+                log_input,
+                log_events,
+                at_line_start: Cell::new(true),
+            };
+        }
+        let (sender, receiver) = sync_channel(CHANNEL_CAPACITY);
+        let writer = LogWriter {
+            outputs,
+            input_log,
+            session_log,
+            output_redactor: config.redact.clone().map(Redactor::new),
+            input_redactors: HashMap::new(),
+            config,
+            pending: Vec::new(),
+            last_message: Instant::now(),
+            unflushed: false,
+            unsynced: false,
+            last_flush: Instant::now(),
+            last_sync: Instant::now(),
+        };
+        Logger {
+            sender: Some(sender),
+            thread: Some(thread::spawn(move || writer.run(receiver))),
+            log_output,
+            log_input,
+            log_events,
+            at_line_start: Cell::new(true),
+        }
+    }
+
+    /// Returns `true` if output is logged anywhere.
+    pub fn logs_output(&self) -> bool {
+        self.log_output
+    }
+
+    /// Logs a chunk of output.
+    pub fn output(&self, buf: &[u8]) {
+        if self.log_output && !buf.is_empty() {
+            self.at_line_start.set(buf.ends_with(b"\n"));
+            self.send(LogMessage::Output(buf.to_vec()));
+        }
+    }
+
+    /// Logs lines into the output, starting on a new line if necessary.
+    pub fn output_lines(&self, lines: &str) {
+        if !self.at_line_start.get() {
+            self.output(b"\n");
+        }
+        self.output(lines.as_bytes());
+    }
+
+    /// Logs a chunk of input read from the given source.
+    pub fn input(&self, source: &str, buf: &[u8]) {
+        if self.log_input {
+            self.send(LogMessage::Input(source.to_string(), buf.to_vec()));
+        }
+    }
+
+    /// Logs a marker in place of input from the given source.
+    pub fn input_marker(&self, source: &str, marker: &'static str) {
+        if self.log_input {
+            self.send(LogMessage::InputMarker(source.to_string(), marker));
+        }
+    }
+
+    /// Logs a session event.
+    pub fn event(&self, event: Event) {
+        if self.log_events {
+            self.send(LogMessage::Event(event));
+        }
+    }
+
+    /// Writes out everything that is left and waits for the thread to finish.
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.sender.take();
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "log writer panicked"))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn send(&self, msg: LogMessage) {
+        if let Some(ref sender) = self.sender {
+            // if the writer failed, the error is reported by `finish`.
+            sender.send(msg).ok();
+        }
+    }
+}
+
+struct LogWriter {
+    outputs: Vec<Output>,
+    input_log: Option<InputLog>,
+    session_log: SessionLog,
+    output_redactor: Option<Redactor>,
+    input_redactors: HashMap<String, Redactor>,
+    config: LogConfig,
+    pending: Vec<u8>,
+    last_message: Instant,
+    unflushed: bool,
+    unsynced: bool,
+    last_flush: Instant,
+    last_sync: Instant,
+}
+
+impl LogWriter {
+    fn run(mut self, receiver: Receiver<LogMessage>) -> Result<(), io::Error> {
+        loop {
+            let msg = match self.next_deadline() {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match receiver.recv_timeout(timeout) {
+                        Ok(msg) => Some(msg),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match receiver.recv() {
+                    Ok(msg) => Some(msg),
+                    Err(_) => break,
+                },
+            };
+
+            // coalesce everything that queued up in the meantime
+            if let Some(msg) = msg {
+                self.handle(msg)?;
+                while self.pending.len() < MAX_COALESCE {
+                    match receiver.try_recv() {
+                        Ok(msg) => self.handle(msg)?,
+                        Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+                    }
+                }
+                self.last_message = Instant::now();
+            } else if self.last_message.elapsed() >= REDACT_IDLE_TIMEOUT {
+                self.release_redacted()?;
+            }
+
+            self.write_pending()?;
+            self.maintain(false)?;
+        }
+
+        self.release_redacted()?;
+        self.write_pending()?;
+        self.maintain(true)?;
+        for output in self.outputs.iter_mut() {
+            output.sink.finish()?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, msg: LogMessage) -> Result<(), io::Error> {
+        match msg {
+            LogMessage::Output(buf) => match self.output_redactor {
+                Some(ref mut redactor) => redactor.feed(&buf, &mut self.pending),
+                None => self.pending.extend_from_slice(&buf),
+            },
+            LogMessage::Input(source, buf) => match self.config.redact {
+                Some(ref regex) => {
+                    let mut released = Vec::new();
+                    self.input_redactors
+                        .entry(source.clone())
+                        .or_insert_with(|| Redactor::new(regex.clone()))
+                        .feed(&buf, &mut released);
+                    self.log_input(&source, &released)?;
+                }
+                None => self.log_input(&source, &buf)?,
+            },
+            LogMessage::InputMarker(source, marker) => {
+                // what's held back for redaction came before the marker
+                if let Some(redactor) = self.input_redactors.get_mut(&source) {
+                    let mut released = Vec::new();
+                    redactor.flush(&mut released);
+                    self.log_input(&source, &released)?;
+                }
+                if let Some(ref mut input_log) = self.input_log {
+                    input_log.marker(&source, marker)?;
+                    self.input_logged()?;
+                }
+            }
+            LogMessage::Event(event) => self.session_log.record(&event)?,
+        }
+        Ok(())
+    }
+
+    fn log_input(&mut self, source: &str, buf: &[u8]) -> Result<(), io::Error> {
+        if let Some(ref mut input_log) = self.input_log {
+            if buf.is_empty() {
+                return Ok(());
+            }
+            input_log.log(source, buf)?;
+            self.input_logged()?;
+        }
+        Ok(())
+    }
+
+    fn input_logged(&mut self) -> Result<(), io::Error> {
+        if let Some(ref mut input_log) = self.input_log {
+            if self.config.flush {
+                input_log.file.flush()?;
+                self.unsynced = true;
+            } else {
+                self.unflushed = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases everything that is held back for redaction.
+    fn release_redacted(&mut self) -> Result<(), io::Error> {
+        if let Some(ref mut redactor) = self.output_redactor {
+            redactor.flush(&mut self.pending);
+        }
+        let mut released = Vec::new();
+        for (source, redactor) in self.input_redactors.iter_mut() {
+            if redactor.has_pending() {
+                let mut buf = Vec::new();
+                redactor.flush(&mut buf);
+                released.push((source.clone(), buf));
+            }
+        }
+        for (source, buf) in released {
+            self.log_input(&source, &buf)?;
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> Result<(), io::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        for output in self.outputs.iter_mut() {
+            output.write(&self.pending, self.config.flush)?;
+            if output.flush.unwrap_or(self.config.flush) {
+                self.unsynced = true;
+            } else {
+                self.unflushed = true;
+            }
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Flushes, syncs and retries sinks as necessary.
+    fn maintain(&mut self, finish: bool) -> Result<(), io::Error> {
+        let now = Instant::now();
+        if self.unflushed
+            && (finish
+                || self
+                    .config
+                    .flush_interval
+                    .map_or(false, |x| now >= self.last_flush + x))
+        {
+            for output in self.outputs.iter_mut() {
+                output.sink.flush()?;
+            }
+            if let Some(ref mut input_log) = self.input_log {
+                input_log.file.flush()?;
+            }
+            self.unflushed = false;
+            self.unsynced = true;
+            self.last_flush = now;
+        }
+
+        if self.unsynced
+            && match self.config.fsync {
+                FsyncPolicy::Never => false,
+                FsyncPolicy::Always => true,
+                FsyncPolicy::Interval(interval) => finish || now >= self.last_sync + interval,
+            }
+        {
+            for output in self.outputs.iter_mut() {
+                output.sink.sync()?;
+            }
+            if let Some(ref mut input_log) = self.input_log {
+                input_log.file.get_ref().sync_data()?;
+            }
+            self.unsynced = false;
+            self.last_sync = now;
+        }
+
+        for output in self.outputs.iter_mut() {
+            if output.sink.has_pending() {
+                output.sink.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the point in time when the writer needs to wake up even if no
+    /// new messages come in.
+    fn next_deadline(&self) -> Option<Instant> {
+        let mut rv = None::<Instant>;
+        let mut consider = |deadline: Instant| {
+            rv = Some(rv.map_or(deadline, |x| x.min(deadline)));
+        };
+        if self.unflushed {
+            if let Some(interval) = self.config.flush_interval {
+                consider(self.last_flush + interval);
+            }
+        }
+        if self.unsynced {
+            if let FsyncPolicy::Interval(interval) = self.config.fsync {
+                consider(self.last_sync + interval);
+            }
+        }
+        if self
+            .output_redactor
+            .as_ref()
+            .map_or(false, |x| x.has_pending())
+            || self.input_redactors.values().any(|x| x.has_pending())
+        {
+            consider(self.last_message + REDACT_IDLE_TIMEOUT);
+        }
+        if self.outputs.iter().any(|x| x.sink.has_pending()) {
+            consider(Instant::now() + STREAM_RETRY_INTERVAL);
+        }
+        rv
+    }
+}
@@ -0,0 +1,167 @@
+//! Parsing of OSC (operating system command) sequences.
+//!
+//! Programs report their window title (OSC 0, 1 and 2) and working directory
+//! (OSC 7) to the terminal with sequences like `ESC ] 2 ; title BEL`.  These
+//! are picked out of the output to track them as session state and can
+//! optionally be stripped from the logs.
+
+/// OSC sequences longer than this are not parsed and passed through.
+const MAX_OSC_LEN: usize = 4096;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+    Esc,
+    Osc,
+    OscEsc,
+}
+
+/// Finds OSC sequences in a stream of chunks.
+pub(crate) struct OscParser {
+    strip: bool,
+    state: State,
+    // the raw bytes of the sequence currently being parsed
+    pending: Vec<u8>,
+}
+
+impl OscParser {
+    pub fn new(strip: bool) -> OscParser {
+        OscParser {
+            strip,
+            state: State::Ground,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Feeds a chunk of output.
+    ///
+    /// The output that should be logged is appended to `out`.  For every
+    /// title or working directory sequence `on_osc` is invoked with the
+    /// command number and its data.  If stripping is enabled, these
+    /// sequences are not passed to `out`; bytes that might belong to such a
+    /// sequence are held back until it's known.
+    pub fn feed<F: FnMut(u32, &[u8])>(&mut self, buf: &[u8], out: &mut Vec<u8>, mut on_osc: F) {
+        if !self.strip {
+            out.extend_from_slice(buf);
+        }
+        for &byte in buf {
+            self.advance(byte, out, &mut on_osc);
+        }
+    }
+
+    fn advance<F: FnMut(u32, &[u8])>(&mut self, byte: u8, out: &mut Vec<u8>, on_osc: &mut F) {
+        match self.state {
+            State::Ground => {
+                if byte == ESC {
+                    self.pending.push(byte);
+                    self.state = State::Esc;
+                } else if self.strip {
+                    out.push(byte);
+                }
+            }
+            State::Esc => {
+                if byte == b']' {
+                    self.pending.push(byte);
+                    self.state = State::Osc;
+                } else {
+                    self.pass_through(out);
+                    self.advance(byte, out, on_osc);
+                }
+            }
+            State::Osc => {
+                self.pending.push(byte);
+                match byte {
+                    BEL => self.finish(1, out, on_osc),
+                    ESC => self.state = State::OscEsc,
+                    _ if self.pending.len() > MAX_OSC_LEN => self.pass_through(out),
+                    _ => {}
+                }
+            }
+            State::OscEsc => {
+                if byte == b'\\' {
+                    self.pending.push(byte);
+                    self.finish(2, out, on_osc);
+                } else {
+                    // the sequence was not terminated, the escape starts
+                    // something new.
+                    self.pending.pop();
+                    self.pass_through(out);
+                    self.advance(ESC, out, on_osc);
+                    self.advance(byte, out, on_osc);
+                }
+            }
+        }
+    }
+
+    /// Handles a complete sequence with a terminator of the given length.
+    fn finish<F: FnMut(u32, &[u8])>(
+        &mut self,
+        terminator: usize,
+        out: &mut Vec<u8>,
+        on_osc: &mut F,
+    ) {
+        let body = &self.pending[2..self.pending.len() - terminator];
+        let (code, data) = match body.iter().position(|&x| x == b';') {
+            Some(idx) => (&body[..idx], &body[idx + 1..]),
+            None => (body, &b""[..]),
+        };
+        let code = std::str::from_utf8(code)
+            .ok()
+            .and_then(|x| x.parse::<u32>().ok());
+        if let Some(code @ (0 | 1 | 2 | 7)) = code {
+            on_osc(code, data);
+            if self.strip {
+                self.pending.clear();
+                self.state = State::Ground;
+                return;
+            }
+        }
+        self.pass_through(out);
+    }
+
+    /// Passes bytes that are still held back to the output at the end.
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        self.pass_through(out);
+    }
+
+    /// Gives up on the pending bytes and passes them to the output.
+    fn pass_through(&mut self, out: &mut Vec<u8>) {
+        if self.strip {
+            out.append(&mut self.pending);
+        } else {
+            self.pending.clear();
+        }
+        self.state = State::Ground;
+    }
+}
+
+/// Extracts the path from the `file://host/path` URL of an OSC 7 sequence.
+pub(crate) fn parse_cwd(data: &[u8]) -> Vec<u8> {
+    let path = match data.strip_prefix(b"file://") {
+        Some(rest) => match rest.iter().position(|&x| x == b'/') {
+            Some(idx) => &rest[idx..],
+            None => rest,
+        },
+        None => data,
+    };
+    let mut rv = Vec::with_capacity(path.len());
+    let mut iter = path.iter();
+    while let Some(&c) = iter.next() {
+        if c == b'%' {
+            let hex = iter
+                .as_slice()
+                .get(..2)
+                .and_then(|x| std::str::from_utf8(x).ok());
+            if let Some(decoded) = hex.and_then(|x| u8::from_str_radix(x, 16).ok()) {
+                rv.push(decoded);
+                iter.nth(1);
+                continue;
+            }
+        }
+        rv.push(c);
+    }
+    rv
+}
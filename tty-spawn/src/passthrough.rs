@@ -0,0 +1,108 @@
+//! Moving output from the pty to the terminal.
+//!
+//! A pty hands out at most a few KiB per read, so a busy program would cost a
+//! poll, a read, a write and a log message per chunk.  Instead the pty is
+//! read in batches: as it's non blocking, reading goes on right away until
+//! nothing is left or the buffer is full.  The buffer grows while batches fill
+//! it and shrinks again once the output calms down.
+//!
+//! If nothing looks at the output (no logs, triggers or parsing of escape
+//! sequences) and stdout is a pipe, Linux can move the output with `splice`
+//! without copying it through userspace at all.
+use std::os::fd::{AsRawFd, BorrowedFd};
+
+use nix::errno::Errno;
+use nix::unistd::read;
+
+/// The size of the buffer when output is slow.
+const MIN_BATCH: usize = 4096;
+
+/// The size the buffer grows to at most.
+const MAX_BATCH: usize = 256 * 1024;
+
+/// How many batches in a row need to use less than a quarter of the buffer
+/// before it shrinks.
+const SHRINK_AFTER: u32 = 16;
+
+/// A buffer for reading output in batches.
+pub(crate) struct ReadBuf {
+    buf: Vec<u8>,
+    small_batches: u32,
+}
+
+impl ReadBuf {
+    pub fn new() -> ReadBuf {
+        ReadBuf {
+            buf: vec![0; MIN_BATCH],
+            small_batches: 0,
+        }
+    }
+
+    /// Reads a batch from a non blocking file descriptor.
+    ///
+    /// Like `read` an empty batch means end of file.  Errors that come up
+    /// after some output was read are left to the next call.
+    pub fn fill(&mut self, fd: BorrowedFd) -> Result<&[u8], Errno> {
+        let mut len = 0;
+        while len < self.buf.len() {
+            match read(fd.as_raw_fd(), &mut self.buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(Errno::EINTR) => {}
+                Err(_) if len > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        self.adapt(len);
+        Ok(&self.buf[..len])
+    }
+
+    fn adapt(&mut self, len: usize) {
+        let size = self.buf.len();
+        if len == size && size < MAX_BATCH {
+            self.buf.resize(size * 2, 0);
+            self.small_batches = 0;
+        } else if len < size / 4 && size > MIN_BATCH {
+            self.small_batches += 1;
+            if self.small_batches >= SHRINK_AFTER {
+                self.buf.truncate(size / 2);
+                self.buf.shrink_to_fit();
+                self.small_batches = 0;
+            }
+        } else {
+            self.small_batches = 0;
+        }
+    }
+}
+
+/// Moves a batch of output into a pipe without copying it through userspace.
+///
+/// Fails with `EINVAL` if the kernel cannot splice from the pty.
+#[cfg(target_os = "linux")]
+pub(crate) fn splice_output(from: BorrowedFd, to: BorrowedFd) -> Result<usize, Errno> {
+    use nix::fcntl::{splice, SpliceFFlags};
+
+    let mut total = 0;
+    while total < MAX_BATCH {
+        match splice(
+            from,
+            None,
+            to,
+            None,
+            MAX_BATCH - total,
+            SpliceFFlags::SPLICE_F_MOVE,
+        ) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(Errno::EINTR) => {}
+            Err(_) if total > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn splice_output(_from: BorrowedFd, _to: BorrowedFd) -> Result<usize, Errno> {
+    Err(Errno::EINVAL)
+}
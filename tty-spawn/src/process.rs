@@ -0,0 +1,88 @@
+//! Resource limits and credentials of the child.
+//!
+//! These are applied in the forked child right before the command is
+//! executed, so they only affect the child and not the process that spawns it.
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{getuid, setgid, setuid, Gid, Uid};
+
+/// A resource limit that can be set for the child.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResourceLimit {
+    /// The CPU time in seconds (`RLIMIT_CPU`).
+    CpuTime,
+    /// The size of the address space in bytes (`RLIMIT_AS`).
+    AddressSpace,
+    /// The number of open files (`RLIMIT_NOFILE`).
+    OpenFiles,
+    /// The size of core files in bytes (`RLIMIT_CORE`).
+    CoreSize,
+}
+
+impl ResourceLimit {
+    fn resource(self) -> Resource {
+        match self {
+            ResourceLimit::CpuTime => Resource::RLIMIT_CPU,
+            ResourceLimit::AddressSpace => Resource::RLIMIT_AS,
+            ResourceLimit::OpenFiles => Resource::RLIMIT_NOFILE,
+            ResourceLimit::CoreSize => Resource::RLIMIT_CORE,
+        }
+    }
+}
+
+/// Settings of the child process.
+#[derive(Default)]
+pub(crate) struct ProcessSettings {
+    pub rlimits: Vec<(ResourceLimit, Option<u64>, Option<u64>)>,
+    pub umask: Option<u32>,
+    pub nice: Option<i32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub groups: Option<Vec<u32>>,
+}
+
+impl ProcessSettings {
+    /// Applies the settings to the current process.
+    ///
+    /// This runs in the forked child and must not allocate.  The credentials
+    /// are changed last, as dropping privileges might prevent the others.
+    pub fn apply(&self) -> Result<(), Errno> {
+        for &(limit, soft, hard) in &self.rlimits {
+            setrlimit(
+                limit.resource(),
+                soft.map_or(libc::RLIM_INFINITY, |x| x as libc::rlim_t),
+                hard.map_or(libc::RLIM_INFINITY, |x| x as libc::rlim_t),
+            )?;
+        }
+        if let Some(mask) = self.umask {
+            umask(Mode::from_bits_truncate(mask as _));
+        }
+        if let Some(nice) = self.nice {
+            Errno::result(unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) })?;
+        }
+
+        // Like std's `Command` the supplementary groups are cleared when
+        // switching users as root unless they are given explicitly.
+        match self.groups {
+            Some(ref groups) => set_groups(groups)?,
+            None if (self.uid.is_some() || self.gid.is_some()) && getuid().is_root() => {
+                set_groups(&[])?
+            }
+            None => {}
+        }
+        if let Some(gid) = self.gid {
+            setgid(Gid::from_raw(gid))?;
+        }
+        if let Some(uid) = self.uid {
+            setuid(Uid::from_raw(uid))?;
+        }
+        Ok(())
+    }
+}
+
+fn set_groups(groups: &[u32]) -> Result<(), Errno> {
+    Errno::result(unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) })?;
+    Ok(())
+}
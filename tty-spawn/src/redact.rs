@@ -0,0 +1,74 @@
+//! Redaction of secrets in logs.
+use regex::bytes::Regex;
+
+/// What matches are replaced with.
+const PLACEHOLDER: &[u8] = b"[REDACTED]";
+
+/// How much of the most recent data is held back because it might be the
+/// start of a match that continues in the next chunk.
+const HOLDBACK: usize = 1024;
+
+/// Combines the redaction patterns into a single regular expression.
+pub(crate) fn compile(patterns: &[String]) -> Option<Regex> {
+    if patterns.is_empty() {
+        return None;
+    }
+    let combined = patterns
+        .iter()
+        .map(|x| format!("(?:{})", x))
+        .collect::<Vec<_>>()
+        .join("|");
+    // the patterns were validated when they were added
+    Regex::new(&combined).ok()
+}
+
+/// Replaces matches in a stream of chunks.
+pub(crate) struct Redactor {
+    regex: Regex,
+    pending: Vec<u8>,
+}
+
+impl Redactor {
+    pub fn new(regex: Regex) -> Redactor {
+        Redactor {
+            regex,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Returns `true` if data is held back.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Feeds a chunk and appends everything that is safe to release to `out`.
+    pub fn feed(&mut self, buf: &[u8], out: &mut Vec<u8>) {
+        self.pending.extend_from_slice(buf);
+        let mut cut = self.pending.len().saturating_sub(HOLDBACK);
+        // a match reaching into the held back data might still grow, so it's
+        // held back as a whole.
+        for m in self.regex.find_iter(&self.pending) {
+            if m.end() > cut {
+                cut = cut.min(m.start());
+                break;
+            }
+        }
+        self.release(cut, out);
+    }
+
+    /// Releases everything that is held back.
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        self.release(self.pending.len(), out);
+    }
+
+    fn release(&mut self, cut: usize, out: &mut Vec<u8>) {
+        let mut last = 0;
+        for m in self.regex.find_iter(&self.pending[..cut]) {
+            out.extend_from_slice(&self.pending[last..m.start()]);
+            out.extend_from_slice(PLACEHOLDER);
+            last = m.end();
+        }
+        out.extend_from_slice(&self.pending[last..cut]);
+        self.pending.drain(..cut);
+    }
+}
@@ -0,0 +1,374 @@
+//! Session state and events.
+//!
+//! The state of a session (which process is in the foreground and the title
+//! and working directory programs report to the terminal) is tracked in the
+//! communication loop.  Changes are written as JSON lines into the events log and the
+//! current state is kept up to date in the status file.  Both are written on
+//! the log writer thread.
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::os::fd::BorrowedFd;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nix::unistd::{tcgetpgrp, Pid};
+
+use crate::osc::parse_cwd;
+
+/// How often the foreground process of the pty is checked.
+const FOREGROUND_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A process (group) in the foreground of the pty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Process {
+    pid: i32,
+    name: Option<String>,
+    cmdline: Vec<String>,
+}
+
+impl Process {
+    /// Looks up the leader of a process group.
+    ///
+    /// The name and command line are only available on Linux where they are
+    /// read from `/proc`.
+    fn lookup(pgid: Pid) -> Process {
+        #[cfg(target_os = "linux")]
+        {
+            let proc_path = format!("/proc/{}", pgid);
+            Process {
+                pid: pgid.as_raw(),
+                name: std::fs::read_to_string(format!("{}/comm", proc_path))
+                    .ok()
+                    .map(|x| x.trim_end().to_string()),
+                cmdline: std::fs::read(format!("{}/cmdline", proc_path))
+                    .map(|x| {
+                        x.split(|&c| c == 0)
+                            .filter(|x| !x.is_empty())
+                            .map(|x| String::from_utf8_lossy(x).into_owned())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Process {
+                pid: pgid.as_raw(),
+                name: None,
+                cmdline: Vec::new(),
+            }
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let mut rv = format!("{{\"pid\":{},\"name\":", self.pid);
+        match self.name {
+            Some(ref name) => write_json_str(&mut rv, name),
+            None => rv.push_str("null"),
+        }
+        rv.push_str(",\"cmdline\":[");
+        for (idx, arg) in self.cmdline.iter().enumerate() {
+            if idx > 0 {
+                rv.push(',');
+            }
+            write_json_str(&mut rv, arg);
+        }
+        rv.push_str("]}");
+        rv
+    }
+}
+
+/// The current state of a session.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionState {
+    pid: i32,
+    foreground: Option<Process>,
+    title: Option<String>,
+    cwd: Option<String>,
+    exit_code: Option<i32>,
+}
+
+impl SessionState {
+    fn to_json(&self) -> String {
+        let mut rv = format!("{{\"pid\":{},\"foreground\":", self.pid);
+        match self.foreground {
+            Some(ref process) => rv.push_str(&process.to_json()),
+            None => rv.push_str("null"),
+        }
+        for (key, value) in [("title", &self.title), ("cwd", &self.cwd)] {
+            write!(rv, ",\"{}\":", key).unwrap();
+            match value {
+                Some(value) => write_json_str(&mut rv, value),
+                None => rv.push_str("null"),
+            }
+        }
+        rv.push_str(",\"exit_code\":");
+        match self.exit_code {
+            Some(code) => write!(rv, "{}", code).unwrap(),
+            None => rv.push_str("null"),
+        }
+        rv.push('}');
+        rv
+    }
+}
+
+/// A change in the session that is reported to the events log.
+pub(crate) struct Event {
+    /// The event as JSON line.
+    pub line: String,
+    /// The state after the event as JSON.
+    pub status: String,
+}
+
+/// Tracks the state of a session.
+pub(crate) struct Session {
+    enabled: bool,
+    state: SessionState,
+    last_check: Option<Instant>,
+}
+
+impl Session {
+    pub fn new(enabled: bool, child: Pid) -> Session {
+        Session {
+            enabled,
+            state: SessionState {
+                pid: child.as_raw(),
+                ..SessionState::default()
+            },
+            last_check: None,
+        }
+    }
+
+    /// Returns how long until the foreground process needs to be checked.
+    pub fn next_check(&self) -> Option<Duration> {
+        if !self.enabled {
+            return None;
+        }
+        Some(match self.last_check {
+            Some(last_check) => FOREGROUND_CHECK_INTERVAL.saturating_sub(last_check.elapsed()),
+            None => Duration::ZERO,
+        })
+    }
+
+    /// Checks if the foreground process of the pty changed if it's time.
+    pub fn check_foreground(&mut self, master: BorrowedFd) -> Option<Event> {
+        // poll works with milliseconds, so anything below is due
+        if self
+            .next_check()
+            .map_or(true, |x| x >= Duration::from_millis(1))
+        {
+            return None;
+        }
+        self.last_check = Some(Instant::now());
+        // there is no foreground process group until the child took over the
+        // pty, in which case 0 is reported.
+        let process = tcgetpgrp(master)
+            .ok()
+            .filter(|x| x.as_raw() > 0)
+            .map(Process::lookup);
+        if process == self.state.foreground {
+            return None;
+        }
+        self.state.foreground = process;
+        let mut line = event_header("foreground");
+        line.push_str(",\"process\":");
+        match self.state.foreground {
+            Some(ref process) => line.push_str(&process.to_json()),
+            None => line.push_str("null"),
+        }
+        line.push('}');
+        Some(self.event(line))
+    }
+
+    /// Records a title (OSC 0 and 2) or working directory (OSC 7) sequence.
+    ///
+    /// OSC 1 sets the icon name which is not tracked.
+    pub fn osc(&mut self, code: u32, data: &[u8]) -> Option<Event> {
+        let (key, value) = match code {
+            0 | 2 => ("title", String::from_utf8_lossy(data).into_owned()),
+            7 => (
+                "cwd",
+                String::from_utf8_lossy(&parse_cwd(data)).into_owned(),
+            ),
+            _ => return None,
+        };
+        let slot = if key == "title" {
+            &mut self.state.title
+        } else {
+            &mut self.state.cwd
+        };
+        if slot.as_ref() == Some(&value) {
+            return None;
+        }
+        let mut line = event_header(key);
+        write!(line, ",\"{}\":", key).unwrap();
+        write_json_str(&mut line, &value);
+        line.push('}');
+        *slot = Some(value);
+        Some(self.event(line))
+    }
+
+    /// Returns `true` if the session is tracked.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Records the exit of the child.
+    pub fn exit(&mut self, code: i32) -> Option<Event> {
+        if !self.enabled {
+            return None;
+        }
+        self.state.exit_code = Some(code);
+        let mut line = event_header("exit");
+        write!(line, ",\"exit_code\":{}}}", code).unwrap();
+        Some(self.event(line))
+    }
+
+    fn event(&self, line: String) -> Event {
+        Event {
+            line,
+            status: self.state.to_json(),
+        }
+    }
+}
+
+/// Starts the JSON object of an event with the timestamp and event type.
+fn event_header(event: &str) -> String {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut rv = format!(
+        "{{\"ts\":{}.{:03},\"event\":",
+        ts.as_secs(),
+        ts.subsec_millis()
+    );
+    write_json_str(&mut rv, event);
+    rv
+}
+
+/// Writes a string as JSON string literal.
+pub(crate) fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// The format of the header and footer written around a run in the logs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogHeaderFormat {
+    /// Lines starting with `#`.
+    Comment,
+    /// A single JSON line each.
+    Json,
+}
+
+/// What is known about a run when it starts.
+pub(crate) struct RunInfo<'a> {
+    pub command: &'a [OsString],
+    pub pid: Pid,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Formats the header written to the logs before the output of a run.
+pub(crate) fn log_header(format: LogHeaderFormat, info: &RunInfo) -> String {
+    let command = info
+        .command
+        .iter()
+        .map(|x| x.to_string_lossy())
+        .collect::<Vec<_>>();
+    let started = format_utc(SystemTime::now());
+    let cwd = env::current_dir()
+        .map(|x| x.display().to_string())
+        .unwrap_or_default();
+    let version = env!("CARGO_PKG_VERSION");
+    match format {
+        LogHeaderFormat::Comment => {
+            let mut rv = String::from("# session start\n");
+            writeln!(rv, "# command: {}", command.join(" ")).unwrap();
+            writeln!(rv, "# started: {}", started).unwrap();
+            writeln!(rv, "# pid: {}", info.pid).unwrap();
+            writeln!(rv, "# cwd: {}", cwd).unwrap();
+            writeln!(rv, "# size: {}x{}", info.cols, info.rows).unwrap();
+            writeln!(rv, "# version: {}", version).unwrap();
+            rv
+        }
+        LogHeaderFormat::Json => {
+            let mut rv = String::from("{\"event\":\"session_start\",\"command\":[");
+            for (idx, arg) in command.iter().enumerate() {
+                if idx > 0 {
+                    rv.push(',');
+                }
+                write_json_str(&mut rv, arg);
+            }
+            rv.push_str("],\"started\":");
+            write_json_str(&mut rv, &started);
+            write!(rv, ",\"pid\":{},\"cwd\":", info.pid).unwrap();
+            write_json_str(&mut rv, &cwd);
+            write!(
+                rv,
+                ",\"cols\":{},\"rows\":{},\"version\":",
+                info.cols, info.rows
+            )
+            .unwrap();
+            write_json_str(&mut rv, version);
+            rv.push_str("}\n");
+            rv
+        }
+    }
+}
+
+/// Formats the footer written to the logs after the output of a run.
+pub(crate) fn log_footer(format: LogHeaderFormat, exit_code: i32, duration: Duration) -> String {
+    let duration = duration.as_secs_f64();
+    match format {
+        LogHeaderFormat::Comment => format!(
+            "# session end\n# exit status: {}\n# duration: {:.3}s\n",
+            exit_code, duration
+        ),
+        LogHeaderFormat::Json => format!(
+            "{{\"event\":\"session_end\",\"exit_code\":{},\"duration\":{:.3}}}\n",
+            exit_code, duration
+        ),
+    }
+}
+
+/// Formats a point in time as RFC 3339 timestamp in UTC.
+fn format_utc(time: SystemTime) -> String {
+    let ts = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = ts.as_secs();
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        ts.subsec_millis()
+    )
+}
@@ -0,0 +1,120 @@
+//! Registering signal handlers for the duration of a spawn.
+//!
+//! signal-hook leaves its handler installed once the last action of a signal
+//! is unregistered, so the signal would be caught and ignored from then on.
+//! Instead the disposition a signal had before is remembered and put back
+//! once nothing is registered for it anymore.  If the signal is registered
+//! again later, the handler of signal-hook is reinstalled as it does not do
+//! that on its own.
+
+// the libc type aliases point to `core::ffi` which is newer than the MSRV,
+// but libc provides them for older compilers too.
+#![allow(clippy::incompatible_msrv)]
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::Mutex;
+
+use nix::libc;
+use signal_hook::SigId;
+
+/// The disposition of a signal that is (or was) registered.
+struct Disposition {
+    signal: libc::c_int,
+    /// What to restore once no [`SignalHooks`] use the signal anymore.
+    prev: libc::sigaction,
+    /// The handler of signal-hook once it was installed.
+    hook: Option<libc::sigaction>,
+    users: usize,
+}
+
+// the actions only hold function pointers and flags.
+unsafe impl Send for Disposition {}
+
+static DISPOSITIONS: Mutex<Vec<Disposition>> = Mutex::new(Vec::new());
+
+/// Signal actions that are unregistered on drop.
+///
+/// When the last registration of a signal goes away, the disposition from
+/// before is restored.
+pub(crate) struct SignalHooks {
+    ids: Vec<SigId>,
+    signals: Vec<libc::c_int>,
+}
+
+impl SignalHooks {
+    pub fn new() -> SignalHooks {
+        SignalHooks {
+            ids: Vec::new(),
+            signals: Vec::new(),
+        }
+    }
+
+    /// Registers an action for a signal with the given signal-hook function.
+    pub fn register<F>(&mut self, signal: libc::c_int, register: F) -> Result<(), io::Error>
+    where
+        F: FnOnce() -> Result<SigId, io::Error>,
+    {
+        let mut dispositions = DISPOSITIONS.lock().unwrap_or_else(|x| x.into_inner());
+        if !self.signals.contains(&signal) {
+            match dispositions.iter_mut().find(|x| x.signal == signal) {
+                Some(disposition) if disposition.users > 0 => disposition.users += 1,
+                Some(disposition) => {
+                    disposition.prev = get_action(signal)?;
+                    if let Some(ref hook) = disposition.hook {
+                        set_action(signal, hook)?;
+                    }
+                    disposition.users = 1;
+                }
+                None => dispositions.push(Disposition {
+                    signal,
+                    prev: get_action(signal)?,
+                    hook: None,
+                    users: 1,
+                }),
+            }
+            self.signals.push(signal);
+        }
+
+        self.ids.push(register()?);
+        if let Some(disposition) = dispositions.iter_mut().find(|x| x.signal == signal) {
+            if disposition.hook.is_none() {
+                disposition.hook = Some(get_action(signal)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SignalHooks {
+    fn drop(&mut self) {
+        let mut dispositions = DISPOSITIONS.lock().unwrap_or_else(|x| x.into_inner());
+        for id in self.ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+        for signal in self.signals.drain(..) {
+            if let Some(disposition) = dispositions.iter_mut().find(|x| x.signal == signal) {
+                disposition.users -= 1;
+                if disposition.users == 0 {
+                    set_action(signal, &disposition.prev).ok();
+                }
+            }
+        }
+    }
+}
+
+fn get_action(signal: libc::c_int) -> Result<libc::sigaction, io::Error> {
+    let mut action = MaybeUninit::<libc::sigaction>::zeroed();
+    if unsafe { libc::sigaction(signal, ptr::null(), action.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { action.assume_init() })
+}
+
+fn set_action(signal: libc::c_int, action: &libc::sigaction) -> Result<(), io::Error> {
+    if unsafe { libc::sigaction(signal, action, ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! Output sinks.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// Receives the output of the spawned process.
+///
+/// Sinks are added with [`TtySpawn::add_sink`](crate::TtySpawn::add_sink).
+/// Output files, FIFOs and sockets are built-in sinks.  All sinks are driven
+/// from the log writer thread, so a slow sink does not hold up the terminal
+/// unless the channel to the writer fills up.
+pub trait OutputSink: Send {
+    /// Writes a chunk of output.
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error>;
+
+    /// Flushes buffered output.
+    ///
+    /// This is invoked after every write if flushing is enabled, otherwise
+    /// in the configured flush interval and at the end.
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Syncs written output to disk according to the fsync policy.
+    fn sync(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Returns `true` if the sink holds back output it could not write yet.
+    ///
+    /// As long as this is the case, [`flush`](Self::flush) is invoked
+    /// periodically even if no new output comes in.
+    fn has_pending(&self) -> bool {
+        false
+    }
+
+    /// Invoked once at the end after everything was written and flushed.
+    fn finish(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// Writes output into a regular file.
+pub(crate) struct FileSink(pub BufWriter<File>);
+
+impl OutputSink for FileSink {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.0.write_all(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.0.flush()
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.0.get_ref().sync_data()
+    }
+}
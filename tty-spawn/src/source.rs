@@ -0,0 +1,176 @@
+//! Input sources.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, IoSlice, Read};
+use std::os::fd::{AsFd, BorrowedFd};
+
+use nix::errno::Errno;
+use nix::sys::uio::writev;
+
+/// Controls what happens when stdin reaches end of file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum StdinEofPolicy {
+    /// Sends end of file (`VEOF`) to the process if the pty is in canonical
+    /// mode.
+    #[default]
+    Eof,
+    /// Keeps the process running, fed only from the other input sources.
+    Ignore,
+    /// Sends `SIGHUP` to the process.
+    Hangup,
+    /// Sends end of file once all other input sources are done.
+    ///
+    /// See [`InputSource::close`] for when a source is done.
+    Wait,
+}
+
+/// Controls what happens when the input buffer of a source is full.
+///
+/// Input is buffered until the pty accepts it, which it doesn't while the
+/// process is not reading.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum InputOverflowPolicy {
+    /// Stops reading from the source until the process caught up.
+    ///
+    /// Whoever writes into the source is blocked in turn.
+    #[default]
+    Block,
+    /// Drops the input that does not fit into the buffer any more.
+    Drop,
+    /// Aborts the spawn with `ENOBUFS`.
+    ///
+    /// The input is not dropped quietly, instead the spawn fails.  The pty is
+    /// closed with it, which hangs up the process.
+    Error,
+}
+
+/// Provides input that is sent to the spawned process.
+///
+/// Sources are added with [`TtySpawn::add_source`](crate::TtySpawn::add_source).
+/// Input files and FIFOs are built-in sources.  The file descriptor returned
+/// from [`AsFd`] is polled for readability together with the pty, once it's
+/// readable [`read`](Self::read) is invoked.
+pub trait InputSource: AsFd + Send {
+    /// The name of the source as recorded in the tagged input log.
+    fn name(&self) -> &str;
+
+    /// Reads a chunk of input.
+    ///
+    /// An error of kind [`io::ErrorKind::WouldBlock`] or
+    /// [`io::ErrorKind::Interrupted`] means that there is no input right now.
+    /// Returning `0` means that the source came to its end, it's not polled
+    /// anymore afterwards.  Any other error aborts the process.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error>;
+
+    /// Lets the source come to an end.
+    ///
+    /// This is invoked with [`StdinEofPolicy::Wait`](crate::StdinEofPolicy::Wait)
+    /// once stdin reached end of file.  FIFOs stop keeping themselves open, so
+    /// they come to their end once nobody writes into them anymore.
+    ///
+    /// End of file is only sent once every source ended, and a source is only
+    /// read when its file descriptor is readable.  So after this was called,
+    /// the file descriptor must become readable once the source has nothing
+    /// more to send and [`read`](Self::read) then has to return `0`.
+    /// Otherwise the process keeps waiting for end of file.  The default does
+    /// nothing, which is fine for sources that end on their own.
+    fn close(&mut self) {}
+}
+
+/// Reads input from a file or FIFO.
+pub(crate) struct FileSource {
+    pub file: File,
+    pub name: String,
+    /// Keeps a FIFO open for writing so that it doesn't report end of file.
+    pub keepalive: Option<File>,
+}
+
+impl AsFd for FileSource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl InputSource for FileSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.file.read(buf)
+    }
+
+    fn close(&mut self) {
+        self.keepalive = None;
+    }
+}
+
+/// Input of one source waiting for the pty to accept it.
+pub(crate) struct InputQueue {
+    buf: VecDeque<u8>,
+    size: usize,
+    overflow: InputOverflowPolicy,
+}
+
+impl InputQueue {
+    pub fn new(size: usize, overflow: InputOverflowPolicy) -> InputQueue {
+        InputQueue {
+            buf: VecDeque::new(),
+            // without any room, blocking sources would never be read again
+            size: size.max(1),
+            overflow,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// How much should be read from the source at most.
+    ///
+    /// When blocking, reading stops once the buffer is full.  Otherwise the
+    /// source is always read so that the overflow policy applies.
+    pub fn read_limit(&self, max: usize) -> usize {
+        match self.overflow {
+            InputOverflowPolicy::Block => max.min(self.size.saturating_sub(self.buf.len())),
+            _ => max,
+        }
+    }
+
+    /// Queues input and returns what was accepted.
+    pub fn push<'a>(&mut self, data: &'a [u8]) -> Result<&'a [u8], Errno> {
+        let space = self.size.saturating_sub(self.buf.len());
+        let data = match self.overflow {
+            // the limit was applied when reading already
+            InputOverflowPolicy::Block => data,
+            InputOverflowPolicy::Drop => &data[..data.len().min(space)],
+            InputOverflowPolicy::Error if data.len() > space => return Err(Errno::ENOBUFS),
+            InputOverflowPolicy::Error => data,
+        };
+        self.buf.extend(data);
+        Ok(data)
+    }
+
+    /// Queues input no matter the limit, such as trigger responses.
+    pub fn push_always(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+    }
+
+    /// Writes as much as possible into a non blocking file descriptor.
+    pub fn write_to(&mut self, fd: BorrowedFd) -> Result<(), Errno> {
+        while !self.buf.is_empty() {
+            let (front, back) = self.buf.as_slices();
+            match writev(fd, &[IoSlice::new(front), IoSlice::new(back)]) {
+                // nothing was taken, try again once the pty is writable
+                Ok(0) => break,
+                Ok(n) => {
+                    self.buf.drain(..n);
+                }
+                Err(Errno::EINTR) => {}
+                Err(Errno::EAGAIN) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
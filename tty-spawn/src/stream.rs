@@ -0,0 +1,274 @@
+//! Stream outputs (FIFOs and sockets).
+//!
+//! Unlike regular files, streams have a receiver on the other end which might
+//! not be there yet, go away or be too slow to keep up.  All streams are
+//! written to without blocking through a bounded buffer.  If there is no
+//! receiver, the stream is (re)connected with an exponential backoff.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, IoSlice, Write};
+use std::net::{self, TcpStream};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::libc::O_NONBLOCK;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+
+use crate::sink::OutputSink;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(250);
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The default size of the buffer of FIFO and socket outputs.
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Controls what happens when the buffer of a FIFO or socket output is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Blocks until the receiver caught up.
+    ///
+    /// Note that this also blocks the terminal.
+    Block,
+    /// Drops the oldest buffered output to make room.
+    #[default]
+    DropOldest,
+    /// Drops the output that does not fit into the buffer any more.
+    DropNewest,
+}
+
+/// The address of a stream output.
+pub(crate) enum StreamAddr {
+    Fifo(PathBuf),
+    Unix(PathBuf),
+    Tcp(Vec<net::SocketAddr>),
+}
+
+enum Stream {
+    Fifo(File),
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn connect(addr: &StreamAddr) -> Result<Stream, io::Error> {
+        match addr {
+            // opening a FIFO for writing in non blocking mode fails with
+            // ENXIO if there is no reader yet.
+            StreamAddr::Fifo(path) => File::options()
+                .write(true)
+                .custom_flags(O_NONBLOCK)
+                .open(path)
+                .map(Stream::Fifo),
+            StreamAddr::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_nonblocking(true)?;
+                Ok(Stream::Unix(stream))
+            }
+            StreamAddr::Tcp(addrs) => {
+                let mut last_err = None;
+                for addr in addrs {
+                    match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+                        Ok(stream) => {
+                            stream.set_nodelay(true).ok();
+                            stream.set_nonblocking(true)?;
+                            return Ok(Stream::Tcp(stream));
+                        }
+                        Err(err) => last_err = Some(err),
+                    }
+                }
+                Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+                }))
+            }
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize, io::Error> {
+        match self {
+            Stream::Fifo(file) => file.write_vectored(bufs),
+            Stream::Unix(stream) => stream.write_vectored(bufs),
+            Stream::Tcp(stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Stream::Fifo(file) => file.as_fd(),
+            Stream::Unix(stream) => stream.as_fd(),
+            Stream::Tcp(stream) => stream.as_fd(),
+        }
+    }
+}
+
+/// Streams output into a FIFO or socket, reconnecting as necessary.
+pub(crate) struct StreamOutput {
+    addr: StreamAddr,
+    stream: Option<Stream>,
+    buffer: VecDeque<u8>,
+    buffer_size: usize,
+    overflow: OverflowPolicy,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl StreamOutput {
+    pub fn new(addr: StreamAddr) -> StreamOutput {
+        StreamOutput {
+            addr,
+            stream: None,
+            buffer: VecDeque::new(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            overflow: OverflowPolicy::default(),
+            backoff: INITIAL_BACKOFF,
+            retry_at: None,
+        }
+    }
+
+    /// Changes the buffer size and overflow policy.
+    pub fn configure(&mut self, buffer_size: usize, overflow: OverflowPolicy) {
+        self.buffer_size = buffer_size.max(1);
+        self.overflow = overflow;
+    }
+
+    /// Queues up the buffer and writes as much as possible without blocking.
+    fn queue(&mut self, mut buf: &[u8]) {
+        let free = self.buffer_size.saturating_sub(self.buffer.len());
+        if buf.len() > free {
+            match self.overflow {
+                OverflowPolicy::Block => {
+                    while !buf.is_empty() {
+                        let free = self.buffer_size - self.buffer.len();
+                        let (now, later) = buf.split_at(buf.len().min(free));
+                        self.buffer.extend(now);
+                        buf = later;
+                        if !buf.is_empty() {
+                            self.wait_for_room();
+                        }
+                    }
+                }
+                OverflowPolicy::DropOldest => {
+                    if buf.len() >= self.buffer_size {
+                        self.buffer.clear();
+                        buf = &buf[buf.len() - self.buffer_size..];
+                    } else {
+                        self.buffer.drain(..buf.len() - free);
+                    }
+                    self.buffer.extend(buf);
+                }
+                OverflowPolicy::DropNewest => {
+                    self.buffer.extend(&buf[..free]);
+                }
+            }
+        } else {
+            self.buffer.extend(buf);
+        }
+        self.pump();
+    }
+
+    /// Writes out buffered data without blocking.
+    ///
+    /// This also reconnects if the backoff period elapsed.
+    fn pump(&mut self) {
+        if self.stream.is_none() && !self.reconnect() {
+            return;
+        }
+        while !self.buffer.is_empty() {
+            let stream = match self.stream {
+                Some(ref mut stream) => stream,
+                None => return,
+            };
+            let (front, back) = self.buffer.as_slices();
+            match stream.write_vectored(&[IoSlice::new(front), IoSlice::new(back)]) {
+                Ok(n) if n > 0 => {
+                    self.buffer.drain(..n);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Ok(_) | Err(_) => {
+                    self.stream = None;
+                    self.schedule_reconnect();
+                }
+            }
+        }
+    }
+
+    /// Blocks until some of the buffer was written out.
+    fn wait_for_room(&mut self) {
+        let len = self.buffer.len();
+        while self.buffer.len() == len {
+            match self.stream {
+                Some(ref stream) => {
+                    let mut fds = [PollFd::new(stream.as_fd(), PollFlags::POLLOUT)];
+                    poll(&mut fds, PollTimeout::NONE).ok();
+                }
+                None => {
+                    if let Some(retry_at) = self.retry_at {
+                        thread::sleep(retry_at.saturating_duration_since(Instant::now()));
+                    }
+                }
+            }
+            self.pump();
+        }
+    }
+
+    fn reconnect(&mut self) -> bool {
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at {
+                return false;
+            }
+        }
+        match Stream::connect(&self.addr) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.backoff = INITIAL_BACKOFF;
+                self.retry_at = None;
+                true
+            }
+            Err(_) => {
+                self.schedule_reconnect();
+                false
+            }
+        }
+    }
+
+    fn schedule_reconnect(&mut self) {
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+impl OutputSink for StreamOutput {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.queue(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.pump();
+        Ok(())
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Writes out what is left in the buffer at the end.
+    ///
+    /// With the blocking overflow policy this waits until the buffer was
+    /// fully written out, otherwise it makes one last attempt.
+    fn finish(&mut self) -> Result<(), io::Error> {
+        if self.overflow == OverflowPolicy::Block {
+            while !self.buffer.is_empty() {
+                self.wait_for_room();
+            }
+        } else {
+            self.pump();
+        }
+        Ok(())
+    }
+}
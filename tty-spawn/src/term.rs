@@ -0,0 +1,278 @@
+//! Restoring the terminal.
+//!
+//! While a process is spawned the terminal is in raw mode and the process
+//! might switch it into modes such as the alternate screen which it doesn't
+//! switch back out of if it crashes.  The original settings and the modes that
+//! are left on are kept in globals, so that they can be restored on every way
+//! out: when [`RestoreTerm`] is dropped, from a panic hook, from an `atexit`
+//! handler and from handlers for fatal signals.
+use std::cell::UnsafeCell;
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Once;
+
+use nix::libc;
+use nix::sys::termios::{
+    cfmakeraw, tcgetattr, tcsetattr, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg,
+    Termios,
+};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+
+use crate::signals::SignalHooks;
+
+/// The private modes that are tracked and the sequences resetting and
+/// setting them.
+///
+/// The cursor visibility (25) is inverted: it's tracked while hidden.
+const MODES: [(u32, &[u8], &[u8]); 11] = [
+    (1049, b"\x1b[?1049l", b"\x1b[?1049h"),
+    (1047, b"\x1b[?1047l", b"\x1b[?1047h"),
+    (47, b"\x1b[?47l", b"\x1b[?47h"),
+    (25, b"\x1b[?25h", b"\x1b[?25l"),
+    (2004, b"\x1b[?2004l", b"\x1b[?2004h"),
+    (1000, b"\x1b[?1000l", b"\x1b[?1000h"),
+    (1002, b"\x1b[?1002l", b"\x1b[?1002h"),
+    (1003, b"\x1b[?1003l", b"\x1b[?1003h"),
+    (1005, b"\x1b[?1005l", b"\x1b[?1005h"),
+    (1006, b"\x1b[?1006l", b"\x1b[?1006h"),
+    (1015, b"\x1b[?1015l", b"\x1b[?1015h"),
+];
+
+/// The fatal signals on which the terminal is restored.
+#[allow(clippy::incompatible_msrv)]
+const FATAL_SIGNALS: [libc::c_int; 4] = [SIGHUP, SIGINT, SIGQUIT, SIGTERM];
+
+/// The modes from [`MODES`] which are currently on, as bits.
+static ACTIVE_MODES: AtomicU32 = AtomicU32::new(0);
+
+/// Set while [`SAVED_ATTRS`] holds settings to restore.
+static SAVED: AtomicBool = AtomicBool::new(false);
+static SAVED_ATTRS: SavedAttrs = SavedAttrs(UnsafeCell::new(MaybeUninit::uninit()));
+
+struct SavedAttrs(UnsafeCell<MaybeUninit<libc::termios>>);
+
+// only written while `SAVED` is not set and only read while it is.
+unsafe impl Sync for SavedAttrs {}
+
+/// Keeps the terminal in raw mode and restores the settings on drop.
+pub(crate) struct RestoreTerm {
+    attrs: Termios,
+    raw_attrs: Termios,
+    _signals: SignalHooks,
+}
+
+impl RestoreTerm {
+    /// Switches the terminal to raw mode.
+    ///
+    /// This remembers the settings to restore and installs the handlers.
+    pub fn enter_raw(attrs: Termios) -> RestoreTerm {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            let prev_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                restore();
+                prev_hook(info);
+            }));
+            unsafe { libc::atexit(restore_at_exit) };
+        });
+
+        if !SAVED.load(Ordering::Acquire) {
+            unsafe { (*SAVED_ATTRS.0.get()).write(attrs.clone().into()) };
+            SAVED.store(true, Ordering::Release);
+        }
+        ACTIVE_MODES.store(0, Ordering::Release);
+
+        // the previous handlers are restored along with the terminal
+        let mut signals = SignalHooks::new();
+        for &signal in FATAL_SIGNALS.iter() {
+            signals
+                .register(signal, || unsafe {
+                    signal_hook::low_level::register(signal, move || {
+                        restore();
+                        signal_hook::low_level::emulate_default_handler(signal).ok();
+                    })
+                })
+                .ok();
+        }
+
+        let mut raw_attrs = attrs.clone();
+        cfmakeraw(&mut raw_attrs);
+        raw_attrs.local_flags.remove(LocalFlags::ECHO);
+        tcsetattr(io::stdin(), SetArg::TCSAFLUSH, &raw_attrs).ok();
+        RestoreTerm {
+            attrs,
+            raw_attrs,
+            _signals: signals,
+        }
+    }
+
+    /// Temporarily restores the terminal, such as when suspended.
+    ///
+    /// Modes the process switched on are reset but remembered.
+    pub fn suspend(&self) {
+        tcsetattr(io::stdin(), SetArg::TCSAFLUSH, &self.attrs).ok();
+        let active = ACTIVE_MODES.load(Ordering::Acquire);
+        for (idx, (_, reset, _)) in MODES.iter().enumerate() {
+            if active & (1 << idx) != 0 {
+                write_stdout(reset);
+            }
+        }
+    }
+
+    /// Switches back to raw mode and the modes of the process.
+    pub fn resume(&self) {
+        let active = ACTIVE_MODES.load(Ordering::Acquire);
+        for (idx, (_, _, set)) in MODES.iter().enumerate() {
+            if active & (1 << idx) != 0 {
+                write_stdout(set);
+            }
+        }
+        tcsetattr(io::stdin(), SetArg::TCSAFLUSH, &self.raw_attrs).ok();
+    }
+}
+
+impl Drop for RestoreTerm {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+extern "C" fn restore_at_exit() {
+    restore();
+}
+
+/// Restores the saved settings and resets modes that were left on.
+///
+/// This is async-signal-safe.
+fn restore() {
+    if SAVED.swap(false, Ordering::AcqRel) {
+        unsafe {
+            libc::tcsetattr(
+                libc::STDIN_FILENO,
+                libc::TCSAFLUSH,
+                (*SAVED_ATTRS.0.get()).as_ptr(),
+            );
+        }
+    }
+    let active = ACTIVE_MODES.swap(0, Ordering::AcqRel);
+    for (idx, (_, reset, _)) in MODES.iter().enumerate() {
+        if active & (1 << idx) != 0 {
+            write_stdout(reset);
+        }
+    }
+}
+
+fn write_stdout(buf: &[u8]) {
+    unsafe { libc::write(libc::STDOUT_FILENO, buf.as_ptr().cast(), buf.len()) };
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+    Esc,
+    Csi,
+    Private,
+}
+
+/// Tracks which private modes the output switches on and off.
+///
+/// These are set with `CSI ? <modes> h` and reset with `CSI ? <modes> l`.
+pub(crate) struct ModeTracker {
+    state: State,
+    params: [u32; 8],
+    count: usize,
+}
+
+impl ModeTracker {
+    pub fn new() -> ModeTracker {
+        ModeTracker {
+            state: State::Ground,
+            params: [0; 8],
+            count: 0,
+        }
+    }
+
+    /// Feeds a chunk of output.
+    pub fn feed(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            self.state = match (self.state, byte) {
+                (_, 0x1b) => State::Esc,
+                (State::Esc, b'[') => State::Csi,
+                (State::Csi, b'?') => {
+                    self.params = [0; 8];
+                    self.count = 1;
+                    State::Private
+                }
+                (State::Private, b'0'..=b'9') => {
+                    let param = &mut self.params[self.count - 1];
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u32);
+                    State::Private
+                }
+                (State::Private, b';') if self.count < self.params.len() => {
+                    self.count += 1;
+                    State::Private
+                }
+                (State::Private, b'h') => {
+                    self.apply(true);
+                    State::Ground
+                }
+                (State::Private, b'l') => {
+                    self.apply(false);
+                    State::Ground
+                }
+                _ => State::Ground,
+            };
+        }
+    }
+
+    fn apply(&self, set: bool) {
+        for &param in &self.params[..self.count] {
+            if let Some(idx) = MODES.iter().position(|x| x.0 == param) {
+                // the cursor is tracked while it's hidden
+                if set != (param == 25) {
+                    ACTIVE_MODES.fetch_or(1 << idx, Ordering::AcqRel);
+                } else {
+                    ACTIVE_MODES.fetch_and(!(1 << idx), Ordering::AcqRel);
+                }
+            }
+        }
+    }
+}
+
+/// Restores sane settings of the terminal connected to stdin.
+///
+/// This undoes raw mode and resets the alternate screen, a hidden cursor,
+/// bracketed paste and mouse reporting, similar to `stty sane` and `reset`.
+/// It's useful if a process that was spawned left the terminal broken, for
+/// instance because it was killed.
+pub fn reset_terminal() -> Result<(), io::Error> {
+    let mut attrs = tcgetattr(io::stdin())?;
+    attrs
+        .input_flags
+        .insert(InputFlags::BRKINT | InputFlags::ICRNL | InputFlags::IXON);
+    attrs
+        .input_flags
+        .remove(InputFlags::INLCR | InputFlags::IGNCR);
+    attrs
+        .output_flags
+        .insert(OutputFlags::OPOST | OutputFlags::ONLCR);
+    attrs.control_flags.insert(ControlFlags::CREAD);
+    attrs.local_flags.insert(
+        LocalFlags::ISIG
+            | LocalFlags::ICANON
+            | LocalFlags::IEXTEN
+            | LocalFlags::ECHO
+            | LocalFlags::ECHOE
+            | LocalFlags::ECHOK
+            | LocalFlags::ECHOCTL
+            | LocalFlags::ECHOKE,
+    );
+    tcsetattr(io::stdin(), SetArg::TCSANOW, &attrs)?;
+    for (_, reset, _) in MODES.iter() {
+        write_stdout(reset);
+    }
+    Ok(())
+}
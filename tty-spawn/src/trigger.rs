@@ -0,0 +1,149 @@
+//! Output triggers that respond with input.
+use std::io;
+use std::time::{Duration, Instant};
+
+use regex::bytes::Regex;
+
+/// How much output is retained to match triggers across chunk boundaries.
+const WINDOW_SIZE: usize = 4096;
+
+/// Sends input to the process when its output matches a pattern.
+///
+/// Triggers are added with [`TtySpawn::add_trigger`](crate::TtySpawn::add_trigger).
+/// The output is matched across chunk boundaries against a rolling window of
+/// the last 4KB of output, so patterns should match less than that.  By default
+/// a trigger only fires once.
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pattern: Regex,
+    response: Vec<u8>,
+    repeat: bool,
+    cooldown: Option<Duration>,
+}
+
+impl Trigger {
+    /// Creates a trigger that sends `response` when the output matches the
+    /// regular expression `pattern`.
+    pub fn new<R: Into<Vec<u8>>>(pattern: &str, response: R) -> Result<Trigger, io::Error> {
+        Ok(Trigger {
+            pattern: Regex::new(pattern)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+            response: response.into(),
+            repeat: false,
+            cooldown: None,
+        })
+    }
+
+    /// Lets the trigger fire every time the pattern matches.
+    pub fn repeat(mut self, yes: bool) -> Trigger {
+        self.repeat = yes;
+        self
+    }
+
+    /// Ignores matches within the given duration after the trigger fired.
+    ///
+    /// This implies [`repeat`](Self::repeat).
+    pub fn cooldown(mut self, cooldown: Duration) -> Trigger {
+        self.repeat = true;
+        self.cooldown = Some(cooldown);
+        self
+    }
+}
+
+struct Rule {
+    trigger: Trigger,
+    fired_at: Option<Instant>,
+}
+
+impl Rule {
+    fn is_armed(&self, now: Instant) -> bool {
+        match self.fired_at {
+            None => true,
+            Some(_) if !self.trigger.repeat => false,
+            Some(fired_at) => self.trigger.cooldown.map_or(true, |x| now >= fired_at + x),
+        }
+    }
+}
+
+/// The recent output of one stream.
+#[derive(Default)]
+struct Window {
+    buf: Vec<u8>,
+    // the absolute offset of the start of `buf` in the stream
+    offset: usize,
+    // the absolute offset up to which each rule already matched
+    consumed: Vec<usize>,
+}
+
+/// Matches the output of the process against a set of triggers.
+pub(crate) struct Triggers {
+    rules: Vec<Rule>,
+    windows: [Window; 2],
+}
+
+impl Triggers {
+    pub fn new(triggers: Vec<Trigger>) -> Triggers {
+        let mut windows = [Window::default(), Window::default()];
+        for window in windows.iter_mut() {
+            window.consumed = vec![0; triggers.len()];
+        }
+        Triggers {
+            rules: triggers
+                .into_iter()
+                .map(|trigger| Rule {
+                    trigger,
+                    fired_at: None,
+                })
+                .collect(),
+            windows,
+        }
+    }
+
+    /// Returns `true` if there are no triggers.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Feeds a chunk of output and invokes `respond` for every trigger that
+    /// fired.
+    ///
+    /// Output of stdout and stderr (in script mode) is matched independently.
+    pub fn feed<F, E>(&mut self, stderr: bool, buf: &[u8], mut respond: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        let window = &mut self.windows[stderr as usize];
+        window.buf.extend_from_slice(buf);
+        let now = Instant::now();
+
+        for (rule, consumed) in self.rules.iter_mut().zip(window.consumed.iter_mut()) {
+            loop {
+                let start = consumed.saturating_sub(window.offset).min(window.buf.len());
+                let m = match rule.trigger.pattern.find(&window.buf[start..]) {
+                    Some(m) => m,
+                    None => break,
+                };
+                // matches are consumed even if the trigger does not fire so
+                // that the same output never fires twice.
+                *consumed = window.offset + start + m.end().max(1);
+                if rule.is_armed(now) {
+                    rule.fired_at = Some(now);
+                    respond(&rule.trigger.response)?;
+                }
+                if start + m.end() >= window.buf.len() {
+                    break;
+                }
+            }
+        }
+
+        if window.buf.len() > WINDOW_SIZE {
+            let excess = window.buf.len() - WINDOW_SIZE;
+            window.buf.drain(..excess);
+            window.offset += excess;
+        }
+        Ok(())
+    }
+}
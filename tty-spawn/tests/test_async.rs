@@ -0,0 +1,43 @@
+#![cfg(feature = "tokio")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use tty_spawn::TtySpawn;
+
+#[tokio::test]
+async fn test_async_input_output() {
+    let mut child = TtySpawn::new("cat").echo(false).spawn_async().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    stdin.write_all(b"hello\n").await.unwrap();
+    let mut output = Vec::new();
+    let mut buf = [0; 1024];
+    while !output.ends_with(b"\n") {
+        let n = stdout.read(&mut buf).await.unwrap();
+        assert!(n > 0, "unexpected end of output: {:?}", output);
+        output.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(output, b"hello\r\n");
+
+    stdin.write_all(b"\x04").await.unwrap();
+    let mut rest = Vec::new();
+    stdout.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, b"");
+    assert_eq!(child.wait().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_async_wait_cancelled() {
+    let mut child = TtySpawn::new_cmdline(["sh", "-c", "sleep 0.3; exit 5"].iter())
+        .spawn_async()
+        .unwrap();
+
+    // the wait is dropped after it started reaping the child
+    tokio::select! {
+        biased;
+        _ = child.wait() => panic!("child exited too early"),
+        _ = std::future::ready(()) => {}
+    }
+    assert_eq!(child.wait().await.unwrap(), 5);
+    assert_eq!(child.wait().await.unwrap(), 5);
+}
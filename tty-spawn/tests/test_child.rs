@@ -0,0 +1,72 @@
+use std::io::{Read, Write};
+
+use tty_spawn::{PtyReader, TtySpawn};
+
+/// Reads from the pty until the output contains `needle`.
+fn read_until(reader: &mut PtyReader, needle: &str) -> String {
+    let mut output = Vec::new();
+    let mut buf = [0; 1024];
+    while !String::from_utf8_lossy(&output).contains(needle) {
+        let n = reader.read(&mut buf).unwrap();
+        assert!(n > 0, "unexpected end of output: {:?}", output);
+        output.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_child_input_output() {
+    let mut child = TtySpawn::new("cat").echo(false).spawn_child().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    stdin.write_all(b"hello\n").unwrap();
+    assert_eq!(read_until(&mut stdout, "\n"), "hello\r\n");
+
+    stdin.write_all(b"\x04").unwrap();
+    let mut rest = String::new();
+    stdout.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+    assert_eq!(child.wait().unwrap(), 0);
+}
+
+#[test]
+fn test_child_resize() {
+    let mut child = TtySpawn::new_cmdline(
+        [
+            "sh",
+            "-c",
+            "trap 'stty size; exit 3' WINCH; stty size; while :; do sleep 0.1; done",
+        ]
+        .iter(),
+    )
+    .spawn_child()
+    .unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    assert_eq!(read_until(&mut stdout, "\n"), "24 80\r\n");
+    child.resize(100, 30).unwrap();
+    let mut rest = String::new();
+    stdout.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "30 100\r\n");
+    assert_eq!(child.wait().unwrap(), 3);
+}
+
+#[test]
+fn test_child_wait() {
+    let mut child = TtySpawn::new_cmdline(["sh", "-c", "echo ready; read x; exit 7"].iter())
+        .spawn_child()
+        .unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    read_until(&mut stdout, "ready");
+    assert_eq!(child.try_wait().unwrap(), None);
+    child.stdin.as_mut().unwrap().write_all(b"\n").unwrap();
+    assert_eq!(child.wait().unwrap(), 7);
+    assert_eq!(child.try_wait().unwrap(), Some(7));
+
+    let mut child = TtySpawn::new("cat").spawn_child().unwrap();
+    assert_eq!(child.try_wait().unwrap(), None);
+    child.kill();
+    assert_eq!(child.wait().unwrap(), 137);
+}
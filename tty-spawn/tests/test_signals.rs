@@ -0,0 +1,92 @@
+use std::env;
+use std::io::Read;
+use std::{mem, ptr};
+
+use nix::libc;
+
+use tty_spawn::TtySpawn;
+
+/// The signals that are handled while spawned.
+const SIGNALS: [(&str, i32); 8] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("TERM", libc::SIGTERM),
+    ("WINCH", libc::SIGWINCH),
+    ("TSTP", libc::SIGTSTP),
+    ("CONT", libc::SIGCONT),
+    ("CHLD", libc::SIGCHLD),
+];
+
+/// Spawns a process twice and reports the signal handlers afterwards.
+///
+/// This runs in a pty started by [`test_signals_restored`] and does nothing
+/// when run on its own.  The spawned process makes us forward the window size
+/// which needs to work again the second time.  In the end `SIGTERM` has to
+/// kill us.
+#[test]
+fn signals_child() {
+    if env::var_os("TTY_SPAWN_SIGNALS_CHILD").is_none() {
+        return;
+    }
+    for _ in 0..2 {
+        TtySpawn::new_cmdline(
+            [
+                "sh",
+                "-c",
+                "trap 'echo resized; exit' WINCH; kill -WINCH $PPID; \
+                 i=0; while [ $i -lt 30 ]; do sleep 0.1; i=$((i+1)); done",
+            ]
+            .iter(),
+        )
+        .spawn()
+        .unwrap();
+    }
+    for (name, signal) in SIGNALS {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        unsafe { libc::sigaction(signal, ptr::null(), &mut action) };
+        let handler = if action.sa_sigaction == libc::SIG_DFL {
+            "default"
+        } else {
+            "caught"
+        };
+        println!("{} {}", name, handler);
+    }
+    unsafe { libc::raise(libc::SIGTERM) };
+    println!("survived");
+}
+
+#[test]
+fn test_signals_restored() {
+    let exe = env::current_exe().unwrap();
+    let mut child = TtySpawn::new_cmdline(
+        [
+            "sh",
+            "-c",
+            "TTY_SPAWN_SIGNALS_CHILD=1 exec \"$0\" signals_child --exact --nocapture",
+            exe.to_str().unwrap(),
+        ]
+        .iter(),
+    )
+    .echo(false)
+    .spawn_child()
+    .unwrap();
+    let mut output = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+
+    assert_eq!(child.wait().unwrap(), 128 + libc::SIGTERM, "{}", output);
+    assert!(!output.contains("survived"), "{}", output);
+    assert_eq!(output.matches("resized").count(), 2, "{}", output);
+    for (name, _) in SIGNALS {
+        assert!(
+            output.contains(&format!("{} default\r\n", name)),
+            "{}",
+            output
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tty_spawn::{InputOverflowPolicy, InputSource, OutputSink, StdinEofPolicy, TtySpawn};
+
+/// Sends input from memory and ends once all of it was read.
+struct MemorySource {
+    data: Vec<u8>,
+    /// Always readable as the other end is closed.
+    ready: UnixStream,
+    reads: Arc<AtomicUsize>,
+}
+
+impl MemorySource {
+    fn new(data: &[u8], reads: Arc<AtomicUsize>) -> MemorySource {
+        MemorySource {
+            data: data.to_vec(),
+            ready: UnixStream::pair().unwrap().0,
+            reads,
+        }
+    }
+}
+
+impl AsFd for MemorySource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.ready.as_fd()
+    }
+}
+
+impl InputSource for MemorySource {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let n = buf.len().min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Collects output in memory.
+#[derive(Default, Clone)]
+struct MemorySink {
+    output: Arc<Mutex<Vec<u8>>>,
+    finished: Arc<Mutex<bool>>,
+}
+
+impl OutputSink for MemorySink {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.output.lock().unwrap().extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), io::Error> {
+        *self.finished.lock().unwrap() = true;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_custom_source_and_sink() {
+    let sink = MemorySink::default();
+    let reads = Arc::new(AtomicUsize::new(0));
+    let code = TtySpawn::new_cmdline(["sh", "-c", "read x; echo \"got $x\"; exit 3"].iter())
+        .echo(false)
+        .stdin_eof(StdinEofPolicy::Ignore)
+        .add_source(MemorySource::new(b"hello\n", Arc::clone(&reads)))
+        .add_sink(sink.clone())
+        .spawn()
+        .unwrap();
+
+    assert_eq!(code, 3);
+    assert_eq!(
+        String::from_utf8(sink.output.lock().unwrap().clone()).unwrap(),
+        "got hello\r\n"
+    );
+    assert!(*sink.finished.lock().unwrap());
+    // the source stays readable, but it's not read anymore once it ended
+    assert_eq!(reads.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_input_overflow_error() {
+    let reads = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let err = TtySpawn::new_cmdline(["sleep", "10"].iter())
+        .stdin_eof(StdinEofPolicy::Ignore)
+        .input_buffer(16, InputOverflowPolicy::Error)
+        .add_source(MemorySource::new(&[b'x'; 64], reads))
+        .spawn()
+        .unwrap_err();
+
+    // the spawn fails and the process is hung up rather than waited for
+    assert_eq!(err.raw_os_error(), Some(nix::libc::ENOBUFS));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_follow_watcher_ends() {
+    fn watchers() -> usize {
+        fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter(|task| {
+                fs::read_to_string(task.as_ref().unwrap().path().join("comm"))
+                    .map_or(false, |x| x.starts_with("tty-spawn-follo"))
+            })
+            .count()
+    }
+
+    fn wait_for_watchers(count: usize) {
+        let started = Instant::now();
+        while watchers() != count {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // following a regular file starts a watcher thread which has to end
+    // with the source even if the file never changes.
+    let mut spawn = TtySpawn::new("true");
+    spawn.stdin_path("Cargo.toml").unwrap();
+    wait_for_watchers(1);
+    drop(spawn);
+    wait_for_watchers(0);
+}